@@ -1,7 +1,20 @@
 use bevy::prelude::*;
 use bevy_renet::*;
 
-use crate::{GameState, UIAssets};
+use crate::{
+    main_menu::ConnectTarget, ConnectSettings, ConnectionError, GameState, ResumeToken, UIAssets,
+};
+
+const BUTTON_MARGIN: UiRect = UiRect {
+    top: Val::Px(10.0),
+    bottom: Val::Px(10.0),
+    left: Val::Px(10.0),
+    right: Val::Px(10.0),
+};
+
+/// Seconds to wait before the first retry, doubled for every further one
+const RETRY_BACKOFF: f32 = 1.0;
+const MAX_RETRY_BACKOFF: f32 = 8.0;
 
 #[derive(Component)]
 struct ConnectingScreen;
@@ -21,6 +34,42 @@ impl Default for DotAnimator {
     }
 }
 
+/// Countdown to the timeout or the next retry
+#[derive(Component)]
+struct StatusText;
+
+#[derive(Component)]
+struct CancelButton;
+
+enum AttemptPhase {
+    /// Waiting for the server to answer until the timer runs out
+    Connecting(Timer),
+    /// The last attempt failed, the next one starts once the timer runs out
+    Retrying { timer: Timer, error: String },
+}
+
+#[derive(Resource)]
+struct ConnectionAttempt {
+    /// Starts at 1 for the first attempt
+    number: u32,
+    retries: u32,
+    phase: AttemptPhase,
+}
+
+impl ConnectionAttempt {
+    fn new(settings: &ConnectSettings, target: &ConnectTarget) -> Self {
+        Self {
+            number: 1,
+            retries: if target.can_retry() {
+                settings.retries
+            } else {
+                0
+            },
+            phase: AttemptPhase::Connecting(Timer::from_seconds(settings.timeout, TimerMode::Once)),
+        }
+    }
+}
+
 pub struct ConnectingScreenPlugin;
 
 impl Plugin for ConnectingScreenPlugin {
@@ -34,12 +83,22 @@ impl Plugin for ConnectingScreenPlugin {
         .add_system_set(
             SystemSet::on_update(GameState::Connecting)
                 .with_system(dot_animation_system)
-                .with_system(handle_client_connection_state),
+                .with_system(handle_client_connection_state)
+                .with_system(connection_attempt_system.after(handle_client_connection_state))
+                .with_system(status_text_system.after(connection_attempt_system))
+                .with_system(handle_cancel_button),
         );
     }
 }
 
-fn setup_connecting_screen(mut commands: Commands, game_assets: Res<UIAssets>) {
+fn setup_connecting_screen(
+    mut commands: Commands,
+    game_assets: Res<UIAssets>,
+    settings: Res<ConnectSettings>,
+    target: Res<ConnectTarget>,
+) {
+    commands.insert_resource(ConnectionAttempt::new(&settings, &target));
+
     commands
         .spawn((
             NodeBundle {
@@ -47,6 +106,7 @@ fn setup_connecting_screen(mut commands: Commands, game_assets: Res<UIAssets>) {
                     align_items: AlignItems::Center,
                     align_self: AlignSelf::Center,
                     justify_content: JustifyContent::Center,
+                    flex_direction: FlexDirection::Column,
                     size: Size::new(Val::Percent(100.), Val::Percent(100.)),
                     padding: UiRect::all(Val::Percent(1.)),
                     ..Default::default()
@@ -67,6 +127,47 @@ fn setup_connecting_screen(mut commands: Commands, game_assets: Res<UIAssets>) {
                 ),
                 DotAnimator::default(),
             ));
+
+            node.spawn((
+                TextBundle::from_section(
+                    String::new(),
+                    TextStyle {
+                        font_size: 20.0,
+                        color: Color::WHITE,
+                        font: game_assets.font.clone(),
+                    },
+                )
+                .with_style(Style {
+                    margin: BUTTON_MARGIN,
+                    ..Default::default()
+                }),
+                StatusText,
+            ));
+
+            node.spawn((
+                ButtonBundle {
+                    background_color: BackgroundColor(Color::rgb(0.1, 0.1, 0.1)),
+                    style: Style {
+                        padding: BUTTON_MARGIN,
+                        margin: BUTTON_MARGIN,
+                        min_size: Size::new(Val::Auto, Val::Px(42.0)),
+                        align_items: AlignItems::Center,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                CancelButton,
+            ))
+            .with_children(|button| {
+                button.spawn(TextBundle::from_section(
+                    "Cancel",
+                    TextStyle {
+                        font_size: 20.0,
+                        color: Color::WHITE,
+                        font: game_assets.font.clone(),
+                    },
+                ));
+            });
         });
 }
 
@@ -74,6 +175,7 @@ fn destroy_connecting_screen(mut commands: Commands, query: Query<Entity, With<C
     if let Ok(entity) = query.get_single() {
         commands.entity(entity).despawn_recursive();
     }
+    commands.remove_resource::<ConnectionAttempt>();
 }
 
 fn dot_animation_system(time: Res<Time>, mut query: Query<(&mut DotAnimator, &mut Text)>) {
@@ -94,9 +196,137 @@ fn dot_animation_system(time: Res<Time>, mut query: Query<(&mut DotAnimator, &mu
 
 fn handle_client_connection_state(
     mut game_state: ResMut<State<GameState>>,
-    client: Res<renet::RenetClient>,
+    client: Option<Res<renet::RenetClient>>,
 ) {
-    if client.is_changed() && client.is_connected() {
-        let _ = game_state.set(GameState::Game);
+    if let Some(client) = client {
+        if client.is_changed() && client.is_connected() {
+            let _ = game_state.set(GameState::Game);
+        };
+    }
+}
+
+/// Give up on attempts which time out or fail, and start new ones with growing delays
+/// until the retries are used up
+#[allow(clippy::too_many_arguments)]
+fn connection_attempt_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut attempt: ResMut<ConnectionAttempt>,
+    mut client: Option<ResMut<renet::RenetClient>>,
+    mut game_state: ResMut<State<GameState>>,
+    mut connection_error: ResMut<ConnectionError>,
+    target: Res<ConnectTarget>,
+    resume_token: Res<ResumeToken>,
+    settings: Res<ConnectSettings>,
+) {
+    let attempt = &mut *attempt;
+
+    let error = match &mut attempt.phase {
+        AttemptPhase::Connecting(timer) => match client.as_deref_mut() {
+            Some(client) => {
+                if client.is_connected() {
+                    return;
+                }
+
+                if let Some(reason) = client.disconnected() {
+                    format!("Connection failed: {}", reason)
+                } else if timer.tick(time.delta()).finished() {
+                    "The server did not respond".to_owned()
+                } else {
+                    return;
+                }
+            }
+            // Resuming a dropped session starts without a client
+            None => match target.create_client(resume_token.0) {
+                Ok(new_client) => {
+                    commands.insert_resource(new_client);
+                    return;
+                }
+                Err(e) => format!("Could not connect: {}", e),
+            },
+        },
+        AttemptPhase::Retrying { timer, .. } => {
+            if !timer.tick(time.delta()).finished() {
+                return;
+            }
+
+            attempt.number += 1;
+            println!("Connection attempt {}", attempt.number);
+
+            match target.create_client(resume_token.0) {
+                Ok(new_client) => {
+                    commands.insert_resource(new_client);
+                    attempt.phase = AttemptPhase::Connecting(Timer::from_seconds(
+                        settings.timeout,
+                        TimerMode::Once,
+                    ));
+                    return;
+                }
+                Err(e) => format!("Could not connect: {}", e),
+            }
+        }
     };
+
+    eprintln!("Connection attempt {} failed: {}", attempt.number, error);
+    if let Some(client) = client.as_deref_mut() {
+        client.disconnect();
+        commands.remove_resource::<renet::RenetClient>();
+    }
+
+    if attempt.number > attempt.retries {
+        connection_error.0 = Some(error);
+        let _ = game_state.set(GameState::MainMenu);
+        return;
+    }
+
+    let backoff = (RETRY_BACKOFF * 2_f32.powi(attempt.number as i32 - 1)).min(MAX_RETRY_BACKOFF);
+    attempt.phase = AttemptPhase::Retrying {
+        timer: Timer::from_seconds(backoff, TimerMode::Once),
+        error,
+    };
+}
+
+fn status_text_system(
+    attempt: Res<ConnectionAttempt>,
+    mut query: Query<&mut Text, With<StatusText>>,
+) {
+    let status = match &attempt.phase {
+        AttemptPhase::Connecting(timer) => format!(
+            "Timing out in {:.0}s (attempt {} of {})",
+            timer.remaining_secs().ceil(),
+            attempt.number,
+            attempt.retries + 1
+        ),
+        AttemptPhase::Retrying { timer, error } => {
+            format!(
+                "{}\nRetrying in {:.0}s",
+                error,
+                timer.remaining_secs().ceil()
+            )
+        }
+    };
+
+    for mut text in query.iter_mut() {
+        text.sections[0].value = status.clone();
+    }
+}
+
+fn handle_cancel_button(
+    mut commands: Commands,
+    mut game_state: ResMut<State<GameState>>,
+    client: Option<ResMut<renet::RenetClient>>,
+    query: Query<&Interaction, (Changed<Interaction>, With<CancelButton>)>,
+) {
+    if !query
+        .iter()
+        .any(|interaction| *interaction == Interaction::Clicked)
+    {
+        return;
+    }
+
+    if let Some(mut client) = client {
+        client.disconnect();
+        commands.remove_resource::<renet::RenetClient>();
+    }
+    let _ = game_state.set(GameState::MainMenu);
 }
@@ -0,0 +1,124 @@
+use bevy::prelude::*;
+
+use crate::{DisconnectReason, GameState, UIAssets};
+
+const BUTTON_MARGIN: UiRect = UiRect {
+    top: Val::Px(10.0),
+    bottom: Val::Px(10.0),
+    left: Val::Px(10.0),
+    right: Val::Px(10.0),
+};
+
+#[derive(Component)]
+struct DisconnectedScreen;
+
+#[derive(Component)]
+struct BackButton;
+
+pub struct DisconnectedScreenPlugin;
+
+impl Plugin for DisconnectedScreenPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_enter(GameState::Disconnected).with_system(setup_disconnected_screen),
+        )
+        .add_system_set(
+            SystemSet::on_exit(GameState::Disconnected).with_system(destroy_disconnected_screen),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameState::Disconnected).with_system(handle_back_button),
+        );
+    }
+}
+
+fn setup_disconnected_screen(
+    mut commands: Commands,
+    disconnect_reason: Res<DisconnectReason>,
+    ui_assets: Res<UIAssets>,
+) {
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    flex_direction: FlexDirection::Column,
+                    size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                    padding: UiRect::all(Val::Percent(1.)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            DisconnectedScreen,
+        ))
+        .with_children(|node| {
+            node.spawn(TextBundle::from_section(
+                disconnect_reason.title.clone(),
+                TextStyle {
+                    font_size: 40.0,
+                    color: Color::WHITE,
+                    font: ui_assets.font.clone(),
+                },
+            ));
+
+            node.spawn(
+                TextBundle::from_section(
+                    disconnect_reason.reason.clone(),
+                    TextStyle {
+                        font_size: 20.0,
+                        color: Color::ORANGE_RED,
+                        font: ui_assets.font.clone(),
+                    },
+                )
+                .with_style(Style {
+                    margin: BUTTON_MARGIN,
+                    ..Default::default()
+                }),
+            );
+
+            node.spawn((
+                ButtonBundle {
+                    background_color: BackgroundColor(Color::rgb(0.1, 0.1, 0.1)),
+                    style: Style {
+                        padding: BUTTON_MARGIN,
+                        margin: BUTTON_MARGIN,
+                        min_size: Size::new(Val::Auto, Val::Px(42.0)),
+                        align_items: AlignItems::Center,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                BackButton,
+            ))
+            .with_children(|button| {
+                button.spawn(TextBundle::from_section(
+                    "Back To Menu",
+                    TextStyle {
+                        font_size: 20.0,
+                        color: Color::WHITE,
+                        font: ui_assets.font.clone(),
+                    },
+                ));
+            });
+        });
+}
+
+fn destroy_disconnected_screen(
+    mut commands: Commands,
+    query: Query<Entity, With<DisconnectedScreen>>,
+) {
+    if let Ok(entity) = query.get_single() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn handle_back_button(
+    mut game_state: ResMut<State<GameState>>,
+    query: Query<&Interaction, (Changed<Interaction>, With<BackButton>)>,
+) {
+    for interaction in query.iter() {
+        if *interaction == Interaction::Clicked {
+            let _ = game_state.set(GameState::MainMenu);
+        }
+    }
+}
@@ -1,19 +1,42 @@
+use std::{path::PathBuf, process};
+
 use bevy::{core_pipeline::clear_color::ClearColorConfig, prelude::*};
 use bevy_renet::*;
+use clap::Parser;
 
 mod connecting_screen;
+mod disconnected_screen;
 mod main_game;
 mod main_menu;
 
 use connecting_screen::ConnectingScreenPlugin;
+use disconnected_screen::DisconnectedScreenPlugin;
 use main_game::MainGamePlugin;
 use main_menu::MainMenuPlugin;
 
+#[derive(Parser, Debug)]
+#[command(about = "Cool Survival Game")]
+struct Args {
+    /// Connect token issued by the server, enables secure mode
+    #[arg(long)]
+    connect_token: Option<PathBuf>,
+
+    /// Seconds to wait for the server to answer a connection attempt
+    #[arg(long, default_value_t = 10.0)]
+    connect_timeout: f32,
+
+    /// How often a failed connection attempt is retried
+    #[arg(long, default_value_t = 3)]
+    connect_retries: u32,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum GameState {
     MainMenu,
     Connecting,
     Game,
+    /// The server dropped the client and told it why
+    Disconnected,
 }
 
 #[derive(Resource)]
@@ -21,6 +44,34 @@ pub struct UIAssets {
     font: Handle<Font>,
 }
 
+/// Connect token to use instead of an unsecure connection, if one was given
+#[derive(Resource)]
+pub struct ConnectTokenFile(pub Option<PathBuf>);
+
+#[derive(Resource)]
+pub struct ConnectSettings {
+    /// Seconds to wait for the server to answer a connection attempt
+    pub timeout: f32,
+    /// How often a failed connection attempt is retried
+    pub retries: u32,
+}
+
+/// Why the last connection attempt ended, shown on the main menu
+#[derive(Resource, Default)]
+pub struct ConnectionError(pub Option<String>);
+
+/// Token from the server's `Welcome`, to take over the same player after losing the
+/// connection
+#[derive(Resource, Default)]
+pub struct ResumeToken(pub Option<u64>);
+
+/// Why the server dropped the client, shown on the disconnected screen
+#[derive(Resource, Default)]
+pub struct DisconnectReason {
+    pub title: String,
+    pub reason: String,
+}
+
 #[derive(Component)]
 pub struct MainCamera {
     pub speed: f32,
@@ -28,6 +79,12 @@ pub struct MainCamera {
 }
 
 fn main() {
+    let args = Args::parse();
+    if !(args.connect_timeout.is_finite() && args.connect_timeout > 0.0) {
+        eprintln!("--connect-timeout must be a positive number");
+        process::exit(1);
+    }
+
     App::new()
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             window: WindowDescriptor {
@@ -42,6 +99,15 @@ fn main() {
         .add_plugin(MainGamePlugin)
         .add_plugin(MainMenuPlugin)
         .add_plugin(ConnectingScreenPlugin)
+        .add_plugin(DisconnectedScreenPlugin)
+        .insert_resource(ConnectTokenFile(args.connect_token))
+        .insert_resource(ConnectSettings {
+            timeout: args.connect_timeout,
+            retries: args.connect_retries,
+        })
+        .insert_resource(ConnectionError::default())
+        .insert_resource(DisconnectReason::default())
+        .insert_resource(ResumeToken::default())
         .add_state(GameState::MainMenu)
         .add_startup_system_to_stage(StartupStage::PreStartup, setup)
         .run();
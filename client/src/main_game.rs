@@ -3,22 +3,33 @@ use std::collections::HashMap;
 use bevy::{prelude::*, render::camera::RenderTarget};
 use bevy_renet::*;
 
-use crate::GameState;
-use shared::*;
+use crate::{main_menu::ConnectTarget, ConnectionError, DisconnectReason, GameState, ResumeToken};
+use shared::{channels::Channel, movement::INPUT_TIMESTEP, snapshot::NetId, *};
 
+mod clock;
+mod health;
+mod interpolation;
 mod orc;
 mod player;
+mod projectile;
 mod slave_player;
+mod snapshot;
+mod wave;
 
+use clock::ClockPlugin;
+use health::HealthPlugin;
+use interpolation::InterpolationPlugin;
 use orc::{events::*, OrcPlugin};
 use player::PlayerPlugin;
+use projectile::ProjectilePlugin;
 use slave_player::{events::*, SlavePlayerPlugin};
+use snapshot::SnapshotPlugin;
+use wave::WavePlugin;
 
-pub const PHYSICS_TIMESTEP: f64 = 1.0 / 60.0; // 60 FPS
+pub const PHYSICS_TIMESTEP: f64 = INPUT_TIMESTEP as f64; // 60 FPS
 
 struct PlayerInfo {
     entity: Entity,
-    username: String,
 }
 
 #[derive(Resource, Default)]
@@ -30,42 +41,101 @@ struct Orcs(HashMap<u64, Entity>);
 #[derive(Resource, Default)]
 struct CursorWorldPosition(Vec2);
 
+/// Whether the join snapshot has arrived. Until then only the block channel is read.
+#[derive(Resource, Default)]
+struct WorldSynced(bool);
+
 pub struct MainGamePlugin;
 
 impl Plugin for MainGamePlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(PlayerPlugin)
+        app.add_plugin(ClockPlugin)
+            .add_plugin(HealthPlugin)
+            .add_plugin(InterpolationPlugin)
+            .add_plugin(PlayerPlugin)
             .add_plugin(SlavePlayerPlugin)
             .add_plugin(OrcPlugin)
+            .add_plugin(ProjectilePlugin)
+            .add_plugin(SnapshotPlugin)
+            .add_plugin(WavePlugin)
             .add_event::<ServerMessage>()
             .add_event::<ClientMessage>()
             .insert_resource(Players::default())
             .insert_resource(Orcs::default())
             .insert_resource(CursorWorldPosition::default())
+            .insert_resource(WorldSynced::default())
+            .add_system_set(SystemSet::on_enter(GameState::Game).with_system(reset_world_sync))
+            .add_system_set(SystemSet::on_exit(GameState::Game).with_system(end_session))
             .add_system_set(
                 SystemSet::on_update(GameState::Game)
                     .with_system(handle_incoming_messages)
                     .with_system(handle_outgoing_messages)
                     .with_system(handle_entity_spawns)
                     .with_system(handle_entity_despawns)
+                    .with_system(handle_disconnect_message)
+                    .with_system(resume_token_system)
+                    // The server's reason is better than whatever the connection reports
+                    .with_system(handle_connection_lost.after(handle_disconnect_message))
                     .with_system(cursor_world_position_system),
             );
     }
 }
 
+fn reset_world_sync(mut world_synced: ResMut<WorldSynced>) {
+    world_synced.0 = false;
+}
+
+/// Drop the connection and forget the world, so the next session starts from scratch.
+/// Each plugin despawns its own entities.
+fn end_session(
+    mut commands: Commands,
+    client: Option<ResMut<renet::RenetClient>>,
+    mut players: ResMut<Players>,
+    mut orcs: ResMut<Orcs>,
+    mut cursor_world_position: ResMut<CursorWorldPosition>,
+) {
+    if let Some(mut client) = client {
+        client.disconnect();
+        commands.remove_resource::<renet::RenetClient>();
+    }
+
+    players.0.clear();
+    orcs.0.clear();
+    *cursor_world_position = CursorWorldPosition::default();
+}
+
 fn handle_incoming_messages(
     mut client: ResMut<renet::RenetClient>,
     mut events: EventWriter<ServerMessage>,
+    mut world_synced: ResMut<WorldSynced>,
 ) {
-    let channel_id = 0;
+    for channel in Channel::ALL {
+        // Leave other messages queued until the snapshot they build upon has arrived
+        if !world_synced.0 && channel != Channel::Chunk {
+            continue;
+        }
 
-    while let Some(serialized_msg) = client.receive_message(channel_id) {
-        match bincode::deserialize(&serialized_msg) {
-            Ok(server_msg) => events.send(server_msg),
-            Err(error) => eprintln!(
-                "An error occured while deserializing server message:\n{}",
-                error
-            ),
+        while let Some(serialized_msg) = client.receive_message(channel.id()) {
+            match wire::deserialize(&serialized_msg) {
+                Ok(server_msg) => {
+                    if let ServerMessage::WorldSnapshot { .. } = server_msg {
+                        world_synced.0 = true;
+                    }
+
+                    events.send(server_msg);
+                }
+                // A server of another version can still say why it drops the client
+                Err(error) => match ServerMessage::read_disconnect_reason(&serialized_msg) {
+                    Some(reason) => events.send(ServerMessage::Disconnected {
+                        reason,
+                        kind: DisconnectKind::VersionMismatch,
+                    }),
+                    None => eprintln!(
+                        "An error occured while deserializing server message:\n{}",
+                        error
+                    ),
+                },
+            }
         }
     }
 }
@@ -74,10 +144,10 @@ fn handle_outgoing_messages(
     mut client: ResMut<renet::RenetClient>,
     mut events: EventReader<ClientMessage>,
 ) {
-    let channel_id = 0;
-
     for client_msg in events.iter() {
-        match bincode::serialize(client_msg) {
+        let channel_id = client_msg.channel().id();
+
+        match wire::serialize(client_msg) {
             Ok(serialized_msg) => client.send_message(channel_id, serialized_msg),
             Err(error) => eprintln!(
                 "An error occured while serializing {:?}:\n{}",
@@ -92,29 +162,38 @@ fn handle_entity_spawns(
     mut spawn_slave_events: EventWriter<SpawnSlavePlayer>,
     mut spawn_orc_events: EventWriter<SpawnOrc>,
 ) {
-    type S = ServerMessage;
-
     for server_msg in server_msg_events.iter() {
-        match server_msg {
-            S::PlayerJoined {
-                id,
-                username,
-                position,
-            } => spawn_slave_events.send(SpawnSlavePlayer {
-                id: *id,
-                username: username.clone(),
-                position: *position,
-            }),
-            S::SpawnOrc {
-                id,
-                position,
-                direction,
-            } => spawn_orc_events.send(SpawnOrc {
-                id: *id,
-                position: *position,
-                direction: *direction,
-            }),
-            _ => {}
+        if let ServerMessage::WorldSnapshot { entities }
+        | ServerMessage::SpawnEntities { entities } = server_msg
+        {
+            for entity in entities {
+                match entity {
+                    EntitySnapshot::Player {
+                        id,
+                        username,
+                        position,
+                        rotation,
+                        health,
+                    } => spawn_slave_events.send(SpawnSlavePlayer {
+                        id: *id,
+                        username: username.clone(),
+                        position: *position,
+                        rotation: *rotation,
+                        health: *health,
+                    }),
+                    EntitySnapshot::Orc {
+                        id,
+                        position,
+                        rotation,
+                        health,
+                    } => spawn_orc_events.send(SpawnOrc {
+                        id: *id,
+                        position: *position,
+                        direction: *rotation,
+                        health: *health,
+                    }),
+                }
+            }
         }
     }
 }
@@ -122,17 +201,80 @@ fn handle_entity_spawns(
 fn handle_entity_despawns(
     mut server_msg_events: EventReader<ServerMessage>,
     mut despawn_slave_events: EventWriter<DespawnSlavePlayer>,
+    mut despawn_orc_events: EventWriter<DespawnOrc>,
 ) {
-    type S = ServerMessage;
+    for server_msg in server_msg_events.iter() {
+        if let ServerMessage::DespawnEntities { ids } = server_msg {
+            for id in ids {
+                match id {
+                    NetId::Player(id) => despawn_slave_events.send(DespawnSlavePlayer { id: *id }),
+                    NetId::Orc(id) => despawn_orc_events.send(DespawnOrc { id: *id }),
+                }
+            }
+        }
+    }
+}
+
+fn handle_disconnect_message(
+    mut commands: Commands,
+    mut server_msg_events: EventReader<ServerMessage>,
+    mut game_state: ResMut<State<GameState>>,
+) {
+    for server_msg in server_msg_events.iter() {
+        if let ServerMessage::Disconnected { reason, kind } = server_msg {
+            commands.insert_resource(DisconnectReason {
+                title: kind.title().to_owned(),
+                reason: reason.clone(),
+            });
+            let _ = game_state.set(GameState::Disconnected);
+        }
+    }
+}
 
+fn resume_token_system(
+    mut server_msg_events: EventReader<ServerMessage>,
+    mut resume_token: ResMut<ResumeToken>,
+) {
     for server_msg in server_msg_events.iter() {
-        match server_msg {
-            S::PlayerLeft { id } => despawn_slave_events.send(DespawnSlavePlayer { id: *id }),
-            _ => {}
+        if let ServerMessage::Welcome {
+            resume_token: token,
+            ..
+        } = server_msg
+        {
+            resume_token.0 = Some(*token);
         }
     }
 }
 
+/// Try to resume the session when the connection ends without a word from the server, like
+/// when it timed out. Without a way to resume, go back to the main menu.
+fn handle_connection_lost(
+    mut connection_error: ResMut<ConnectionError>,
+    mut game_state: ResMut<State<GameState>>,
+    client: Res<renet::RenetClient>,
+    resume_token: Res<ResumeToken>,
+    target: Res<ConnectTarget>,
+) {
+    let reason = match client.disconnected() {
+        Some(reason) => format!("Lost connection to the server: {}", reason),
+        None => return,
+    };
+
+    if resume_token.0.is_some() && target.can_resume() {
+        // The server holds the player for a while, the connecting screen takes it from here
+        if game_state.set(GameState::Connecting).is_ok() {
+            println!("{}, trying to resume", reason);
+        }
+        return;
+    }
+
+    // Leaving for another reason already
+    if game_state.set(GameState::MainMenu).is_ok() {
+        println!("{}", reason);
+        connection_error.0 = Some(reason);
+    }
+}
+
 fn cursor_world_position_system(
     windows: Res<Windows>,
     query: Query<(&Camera, &GlobalTransform)>,
@@ -147,7 +289,7 @@ fn cursor_world_position_system(
     };
 
     if let Some(screen_pos) = window.cursor_position() {
-        let window_size = Vec2::new(window.width() as f32, window.height() as f32);
+        let window_size = Vec2::new(window.width(), window.height());
 
         let ndc = (screen_pos / window_size) * 2.0 - Vec2::ONE;
         let ndc_to_world = camera_transform.compute_matrix() * camera.projection_matrix().inverse();
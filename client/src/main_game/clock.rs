@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::GameState;
+use shared::*;
+
+/// Seconds between two pings
+const PING_INTERVAL: f64 = 0.5;
+/// Pongs the estimate is based on
+const MAX_CLOCK_SAMPLES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ClockSample {
+    rtt: f64,
+    /// Server tick minus local time in ticks
+    offset: f64,
+}
+
+/// Estimate of the server's clock, from pings answered by the server
+#[derive(Resource, Default)]
+pub struct ServerClock {
+    pub tick_rate: f64,
+    samples: VecDeque<ClockSample>,
+}
+
+impl ServerClock {
+    pub fn reset(&mut self, tick_rate: f64) {
+        *self = Self {
+            tick_rate,
+            ..Default::default()
+        };
+    }
+
+    /// Add the answer to a ping sent at `sent_at` which arrived at `now`, both local times
+    pub fn observe(&mut self, sent_at: f64, now: f64, tick: u64) {
+        let rtt = (now - sent_at).max(0.0);
+        // The server answered about halfway through the round trip
+        let offset = tick as f64 + rtt / 2.0 * self.tick_rate - now * self.tick_rate;
+
+        self.samples.push_back(ClockSample { rtt, offset });
+        if self.samples.len() > MAX_CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Average round trip time in seconds
+    pub fn rtt(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        Some(self.samples.iter().map(|sample| sample.rtt).sum::<f64>() / self.samples.len() as f64)
+    }
+
+    /// Server tick at local time `now`. Pongs which took the shortest way back waited in
+    /// the fewest queues, so their offset is trusted the most.
+    pub fn tick(&self, now: f64) -> Option<f64> {
+        let best = self.samples.iter().min_by(|a, b| a.rtt.total_cmp(&b.rtt))?;
+
+        Some(best.offset + now * self.tick_rate)
+    }
+}
+
+pub struct ClockPlugin;
+
+impl Plugin for ClockPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ServerClock::default())
+            .add_system_set(
+                SystemSet::on_update(GameState::Game)
+                    .with_system(ping_system)
+                    .with_system(pong_system),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Game).with_system(reset_clock));
+    }
+}
+
+fn reset_clock(mut clock: ResMut<ServerClock>) {
+    *clock = ServerClock::default();
+}
+
+fn ping_system(
+    mut last_ping: Local<Option<f64>>,
+    time: Res<Time>,
+    mut client_msg_events: EventWriter<ClientMessage>,
+) {
+    let now = time.elapsed_seconds_f64();
+    if last_ping.is_some_and(|last_ping| now - last_ping < PING_INTERVAL) {
+        return;
+    }
+    *last_ping = Some(now);
+
+    client_msg_events.send(ClientMessage::Ping { client_time: now });
+}
+
+fn pong_system(
+    time: Res<Time>,
+    mut events: EventReader<ServerMessage>,
+    mut clock: ResMut<ServerClock>,
+) {
+    for event in events.iter() {
+        match event {
+            ServerMessage::Welcome { tick_rate, .. } => clock.reset(*tick_rate),
+            ServerMessage::Pong { client_time, tick } => {
+                clock.observe(*client_time, time.elapsed_seconds_f64(), *tick)
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusts_the_fastest_pong() {
+        let mut clock = ServerClock::default();
+        clock.reset(60.0);
+
+        // Answered at tick 600, half a round trip of 0.1s before arriving at 10.05
+        clock.observe(9.95, 10.05, 600);
+        // Delayed on the way back, which makes the server look 0.1s behind
+        clock.observe(10.0, 10.4, 606);
+
+        let tick = clock.tick(11.0).unwrap();
+        assert!((tick - 660.0).abs() < 1e-6, "{}", tick);
+        assert!((clock.rtt().unwrap() - 0.25).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,193 @@
+use bevy::{prelude::*, sprite::Anchor};
+
+use crate::{GameState, UIAssets};
+use shared::*;
+
+const HEALTH_BAR_SIZE: Vec2 = Vec2::new(48.0, 5.0);
+const HEALTH_BAR_OFFSET: Vec3 = Vec3::new(-HEALTH_BAR_SIZE.x / 2.0, 36.0, 2.0);
+const HEALTH_BAR_COLOR: Color = Color::rgb(0.85, 0.15, 0.15);
+/// Tint of remote players who are down
+const DOWNED_COLOR: Color = Color::rgba(0.5, 0.5, 0.5, 0.6);
+
+/// Share of the maximum health a remote player or orc has left
+#[derive(Component)]
+pub struct Health(pub f32);
+
+/// Shows the health of `owner` above it while it is hurt
+#[derive(Component)]
+struct HealthBar {
+    owner: Entity,
+}
+
+/// What the server last said about the local player's health
+#[derive(Resource, Default)]
+pub struct LocalHealth {
+    health: f32,
+    max_health: f32,
+    /// Elapsed time at which the player respawns, while it is down
+    respawn_at: Option<f64>,
+}
+
+impl LocalHealth {
+    pub fn is_down(&self) -> bool {
+        self.respawn_at.is_some()
+    }
+}
+
+#[derive(Component)]
+struct HealthText;
+
+pub struct HealthPlugin;
+
+impl Plugin for HealthPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(LocalHealth::default())
+            .add_system_set(SystemSet::on_enter(GameState::Game).with_system(setup_health_hud))
+            .add_system_set(
+                SystemSet::on_update(GameState::Game)
+                    .with_system(local_health_system)
+                    .with_system(health_text_system.after(local_health_system))
+                    .with_system(spawn_health_bar_system)
+                    .with_system(health_bar_system)
+                    .with_system(downed_tint_system),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Game).with_system(destroy_health_hud));
+    }
+}
+
+fn setup_health_hud(mut commands: Commands, ui_assets: Res<UIAssets>) {
+    commands.spawn((
+        TextBundle::from_section(
+            String::new(),
+            TextStyle {
+                font_size: 24.0,
+                color: Color::WHITE,
+                font: ui_assets.font.clone(),
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                left: Val::Px(10.0),
+                bottom: Val::Px(10.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+        HealthText,
+    ));
+}
+
+type HealthHudEntities<'w, 's> = Query<'w, 's, Entity, Or<(With<HealthText>, With<HealthBar>)>>;
+
+fn destroy_health_hud(
+    mut commands: Commands,
+    mut local_health: ResMut<LocalHealth>,
+    query: HealthHudEntities,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+    *local_health = LocalHealth::default();
+}
+
+fn local_health_system(
+    time: Res<Time>,
+    mut server_msg_events: EventReader<ServerMessage>,
+    mut local_health: ResMut<LocalHealth>,
+) {
+    for server_msg in server_msg_events.iter() {
+        match server_msg {
+            ServerMessage::PlayerHealth { health, max_health } => {
+                local_health.health = *health;
+                local_health.max_health = *max_health;
+            }
+            ServerMessage::PlayerDowned { respawn_in } => {
+                local_health.respawn_at = Some(time.elapsed_seconds_f64() + *respawn_in as f64);
+            }
+            ServerMessage::PlayerRespawned { .. } => {
+                local_health.respawn_at = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn health_text_system(
+    time: Res<Time>,
+    local_health: Res<LocalHealth>,
+    mut query: Query<&mut Text, With<HealthText>>,
+) {
+    let text_value = match local_health.respawn_at {
+        Some(respawn_at) => format!(
+            "You are down! Respawning in {:.0}s",
+            (respawn_at - time.elapsed_seconds_f64()).max(0.0).ceil()
+        ),
+        // Nothing heard from the server yet
+        None if local_health.max_health <= 0.0 => String::new(),
+        None => format!(
+            "Health {:.0}/{:.0}",
+            local_health.health.ceil(),
+            local_health.max_health
+        ),
+    };
+
+    for mut text in query.iter_mut() {
+        text.sections[0].value = text_value.clone();
+    }
+}
+
+fn spawn_health_bar_system(mut commands: Commands, query: Query<Entity, Added<Health>>) {
+    for owner in query.iter() {
+        commands.spawn((
+            SpriteBundle {
+                sprite: Sprite {
+                    color: HEALTH_BAR_COLOR,
+                    custom_size: Some(HEALTH_BAR_SIZE),
+                    anchor: Anchor::CenterLeft,
+                    ..Default::default()
+                },
+                visibility: Visibility { is_visible: false },
+                ..Default::default()
+            },
+            HealthBar { owner },
+        ));
+    }
+}
+
+/// Keep bars on top of their owners, and remove them along with their owners
+fn health_bar_system(
+    mut commands: Commands,
+    owner_query: Query<(&Transform, &Health), Without<HealthBar>>,
+    mut bar_query: Query<(
+        Entity,
+        &HealthBar,
+        &mut Transform,
+        &mut Sprite,
+        &mut Visibility,
+    )>,
+) {
+    for (entity, bar, mut bar_tf, mut sprite, mut visibility) in bar_query.iter_mut() {
+        let (owner_tf, health) = match owner_query.get(bar.owner) {
+            Ok(owner) => owner,
+            Err(_) => {
+                commands.entity(entity).despawn();
+                continue;
+            }
+        };
+
+        bar_tf.translation = owner_tf.translation + HEALTH_BAR_OFFSET;
+        sprite.custom_size = Some(Vec2::new(HEALTH_BAR_SIZE.x * health.0, HEALTH_BAR_SIZE.y));
+        visibility.is_visible = health.0 > 0.0 && health.0 < 1.0;
+    }
+}
+
+fn downed_tint_system(mut query: Query<(&Health, &mut TextureAtlasSprite), Changed<Health>>) {
+    for (health, mut sprite) in query.iter_mut() {
+        sprite.color = if health.0 > 0.0 {
+            Color::WHITE
+        } else {
+            DOWNED_COLOR
+        };
+    }
+}
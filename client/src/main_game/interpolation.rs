@@ -0,0 +1,268 @@
+use std::{
+    collections::VecDeque,
+    f32::consts::{PI, TAU},
+};
+
+use bevy::prelude::*;
+
+use super::clock::ServerClock;
+use crate::GameState;
+use shared::ServerMessage;
+
+/// Samples kept per entity, well beyond any sensible render delay
+const MAX_SAMPLES: usize = 64;
+
+#[derive(Resource)]
+pub struct InterpolationSettings {
+    /// How far in the past remote entities are rendered, in server ticks
+    pub delay_ticks: f64,
+    /// How far past the newest sample an entity may be extrapolated before it holds still, in
+    /// server ticks
+    pub max_extrapolation_ticks: f64,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        Self {
+            delay_ticks: 6.0,
+            max_extrapolation_ticks: 3.0,
+        }
+    }
+}
+
+/// Server tick at which remote entities are currently rendered
+#[derive(Resource, Default)]
+pub struct RenderClock {
+    pub tick: f64,
+    pub tick_rate: f64,
+    /// Newest server tick received so far
+    latest_tick: Option<u64>,
+}
+
+impl RenderClock {
+    pub fn reset(&mut self, tick_rate: f64) {
+        *self = Self {
+            tick_rate,
+            ..Default::default()
+        };
+    }
+
+    pub fn observe(&mut self, tick: u64) {
+        if self.latest_tick.is_none_or(|latest| tick > latest) {
+            self.latest_tick = Some(tick);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sample {
+    tick: u64,
+    position: Vec2,
+    rotation: f32,
+}
+
+#[derive(Component, Default)]
+pub struct Interpolated {
+    samples: VecDeque<Sample>,
+}
+
+impl Interpolated {
+    /// Add a transform received from the server, ignoring duplicates and late packets
+    pub fn push(&mut self, tick: u64, position: Vec2, rotation: f32) {
+        if self.samples.back().is_some_and(|last| tick <= last.tick) {
+            return;
+        }
+
+        self.samples.push_back(Sample {
+            tick,
+            position,
+            rotation,
+        });
+
+        if self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Position and rotation at `tick`. Up to `max_extrapolation` ticks past the newest
+    /// sample the entity keeps moving, after that it holds at the newest sample.
+    pub fn sample(&self, tick: f64, max_extrapolation: f64) -> Option<(Vec2, f32)> {
+        let newest = *self.samples.back()?;
+        let oldest = *self.samples.front()?;
+
+        if tick <= oldest.tick as f64 {
+            return Some((oldest.position, oldest.rotation));
+        }
+
+        if tick >= newest.tick as f64 {
+            // Keep moving the way the entity last moved, for a little while
+            let previous = match self.samples.len() {
+                1 => return Some((newest.position, newest.rotation)),
+                len => self.samples[len - 2],
+            };
+            let ahead = tick - newest.tick as f64;
+            // Updates stopped for longer than a lost packet or two, most likely because the
+            // entity came to rest and unchanged entities are left out of snapshots
+            if ahead > max_extrapolation {
+                return Some((newest.position, newest.rotation));
+            }
+            let t = 1.0 + ahead / (newest.tick - previous.tick) as f64;
+
+            return Some(lerp(&previous, &newest, t as f32));
+        }
+
+        let (from, to) = self
+            .samples
+            .iter()
+            .zip(self.samples.iter().skip(1))
+            .find(|(_, to)| to.tick as f64 >= tick)?;
+        let t = (tick - from.tick as f64) / (to.tick - from.tick) as f64;
+
+        Some(lerp(from, to, t as f32))
+    }
+
+    /// Drop samples which are no longer needed to render `tick`
+    fn prune(&mut self, tick: f64) {
+        while self.samples.len() > 2 && self.samples[1].tick as f64 <= tick {
+            self.samples.pop_front();
+        }
+    }
+}
+
+fn lerp(from: &Sample, to: &Sample, t: f32) -> (Vec2, f32) {
+    // Rotate the short way around
+    let mut delta = (to.rotation - from.rotation) % TAU;
+    if delta > PI {
+        delta -= TAU;
+    } else if delta < -PI {
+        delta += TAU;
+    }
+
+    (
+        from.position.lerp(to.position, t),
+        from.rotation + delta * t,
+    )
+}
+
+pub struct InterpolationPlugin;
+
+impl Plugin for InterpolationPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(InterpolationSettings::default())
+            .insert_resource(RenderClock::default())
+            .add_system_set(
+                SystemSet::on_update(GameState::Game)
+                    .with_system(welcome_system)
+                    .with_system(render_clock_system)
+                    .with_system(interpolation_system),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Game).with_system(reset_render_clock));
+    }
+}
+
+fn reset_render_clock(mut clock: ResMut<RenderClock>) {
+    *clock = RenderClock::default();
+}
+
+fn welcome_system(mut events: EventReader<ServerMessage>, mut clock: ResMut<RenderClock>) {
+    for event in events.iter() {
+        if let ServerMessage::Welcome { tick_rate, .. } = event {
+            clock.reset(*tick_rate);
+        }
+    }
+}
+
+/// Advance the render tick with local time, steering it towards the delayed newest tick
+fn render_clock_system(
+    time: Res<Time>,
+    settings: Res<InterpolationSettings>,
+    server_clock: Res<ServerClock>,
+    mut clock: ResMut<RenderClock>,
+) {
+    // Snapshots sent at the estimated server tick are still half a round trip away. Until
+    // there is an estimate, the newest snapshot which arrived has to do.
+    let estimated_tick = server_clock
+        .tick(time.elapsed_seconds_f64())
+        .zip(server_clock.rtt())
+        .map(|(tick, rtt)| tick - rtt / 2.0 * server_clock.tick_rate);
+    let latest_tick = match estimated_tick.or(clock.latest_tick.map(|tick| tick as f64)) {
+        Some(latest_tick) => latest_tick,
+        None => return,
+    };
+
+    let target = latest_tick - settings.delay_ticks;
+    let error = target - clock.tick;
+
+    if error.abs() > settings.delay_ticks * 2.0 {
+        // Too far off to catch up smoothly, like right after joining
+        clock.tick = target;
+    } else {
+        // Run up to 10% faster or slower until the error is gone
+        let speed = 1.0 + (error / settings.delay_ticks).clamp(-1.0, 1.0) * 0.1;
+        clock.tick += time.delta_seconds_f64() * clock.tick_rate * speed;
+    }
+}
+
+fn interpolation_system(
+    settings: Res<InterpolationSettings>,
+    clock: Res<RenderClock>,
+    mut query: Query<(&mut Transform, &mut Interpolated)>,
+) {
+    for (mut transform, mut interpolated) in query.iter_mut() {
+        interpolated.prune(clock.tick);
+
+        if let Some((position, rotation)) =
+            interpolated.sample(clock.tick, settings.max_extrapolation_ticks)
+        {
+            transform.translation = position.extend(transform.translation.z);
+            transform.rotation = Quat::from_rotation_z(rotation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer() -> Interpolated {
+        let mut interpolated = Interpolated::default();
+        interpolated.push(10, Vec2::new(0.0, 0.0), 0.0);
+        interpolated.push(12, Vec2::new(20.0, 0.0), 0.0);
+        interpolated
+    }
+
+    #[test]
+    fn interpolates_between_samples() {
+        let (position, _) = buffer().sample(11.0, 3.0).unwrap();
+        assert_eq!(position, Vec2::new(10.0, 0.0));
+    }
+
+    #[test]
+    fn limits_extrapolation() {
+        let (position, _) = buffer().sample(13.0, 2.0).unwrap();
+        assert_eq!(position, Vec2::new(30.0, 0.0));
+
+        // Holds at the newest sample once updates stopped for too long
+        let (position, _) = buffer().sample(20.0, 2.0).unwrap();
+        assert_eq!(position, Vec2::new(20.0, 0.0));
+    }
+
+    #[test]
+    fn ignores_late_samples() {
+        let mut interpolated = buffer();
+        interpolated.push(11, Vec2::new(100.0, 0.0), 0.0);
+
+        let (position, _) = interpolated.sample(11.0, 3.0).unwrap();
+        assert_eq!(position, Vec2::new(10.0, 0.0));
+    }
+
+    #[test]
+    fn rotates_the_short_way() {
+        let mut interpolated = Interpolated::default();
+        interpolated.push(0, Vec2::ZERO, PI - 0.1);
+        interpolated.push(2, Vec2::ZERO, -PI + 0.1);
+
+        let (_, rotation) = interpolated.sample(1.0, 0.0).unwrap();
+        assert!((rotation - PI).abs() < 1e-5);
+    }
+}
@@ -2,9 +2,8 @@ use std::f32::consts::FRAC_PI_2;
 
 use bevy::prelude::*;
 
-use super::Orcs;
+use super::{health::Health, interpolation::Interpolated, Orcs};
 use crate::GameState;
-use shared::ServerMessage;
 
 pub mod events {
     use bevy::prelude::Vec2;
@@ -13,11 +12,16 @@ pub mod events {
         pub id: u64,
         pub position: Vec2,
         pub direction: f32,
+        pub health: f32,
+    }
+
+    pub struct DespawnOrc {
+        pub id: u64,
     }
 }
 
 #[derive(Component)]
-struct Orc(u64);
+struct Orc;
 
 #[derive(Resource)]
 struct OrcAssets {
@@ -29,11 +33,15 @@ pub struct OrcPlugin;
 impl Plugin for OrcPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<events::SpawnOrc>()
+            .add_event::<events::DespawnOrc>()
             .add_startup_system(setup_orc)
             .add_system_set(
                 SystemSet::on_update(GameState::Game)
                     .with_system(spawn_orc_system)
-                    .with_system(orc_transform_update_system),
+                    .with_system(despawn_orc_system),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameState::Game).with_system(despawn_all_orcs_system),
             );
     }
 }
@@ -59,17 +67,25 @@ fn spawn_orc_system(
     orc_assets: Res<OrcAssets>,
 ) {
     for event in events.iter() {
+        // Keep the entity of anything which is spawned already
+        if orcs.0.contains_key(&event.id) {
+            continue;
+        }
+
         let entity = commands
             .spawn((
                 SpriteSheetBundle {
                     texture_atlas: orc_assets.idle.clone(),
                     transform: Transform {
                         translation: event.position.extend(0.0),
+                        rotation: Quat::from_rotation_z(event.direction - FRAC_PI_2),
                         ..Default::default()
                     },
                     ..Default::default()
                 },
-                Orc(event.id),
+                Orc,
+                Interpolated::default(),
+                Health(event.health),
             ))
             .id();
 
@@ -78,32 +94,20 @@ fn spawn_orc_system(
     }
 }
 
-fn orc_transform_update_system(
-    mut events: EventReader<ServerMessage>,
-    mut query: Query<&mut Transform, With<Orc>>,
-    orcs: Res<Orcs>,
+fn despawn_orc_system(
+    mut commands: Commands,
+    mut events: EventReader<events::DespawnOrc>,
+    mut orcs: ResMut<Orcs>,
 ) {
     for event in events.iter() {
-        if let ServerMessage::OrcTransformUpdate {
-            id,
-            position,
-            rotation,
-        } = event
-        {
-            // Get orc entity from orc id
-            let entity = orcs.0.get(id);
-            if entity.is_none() {
-                continue;
-            }
-            let entity = entity.unwrap();
-
-            if let Ok(mut orc_tf) = query.get_mut(*entity) {
-                // Mutate entity's transform
-                // FIXME: understand quaternions
-                orc_tf.translation = position.extend(0.0);
-                orc_tf.rotation = Quat::from_rotation_z(*rotation);
-                dbg!(rotation, rotation * 3.141592);
-            }
+        if let Some(entity) = orcs.0.remove(&event.id) {
+            commands.entity(entity).despawn();
         }
     }
 }
+
+fn despawn_all_orcs_system(mut commands: Commands, query: Query<Entity, With<Orc>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+}
@@ -1,17 +1,30 @@
-use std::f32::consts::FRAC_PI_2;
+use std::{collections::VecDeque, f32::consts::FRAC_PI_2};
 
 use bevy::{prelude::*, time::FixedTimestep};
 
-use shared::*;
+use shared::{
+    movement::{move_player, PlayerInput, MAX_INPUTS_PER_MESSAGE},
+    *,
+};
 
-use super::{CursorWorldPosition, PlayerInfo, Players, PHYSICS_TIMESTEP};
-use crate::{main_menu::MyUsername, GameState, MainCamera};
-
-const PLAYER_SPEED: f32 = 300.0;
+use super::{
+    health::LocalHealth, interpolation::RenderClock, CursorWorldPosition, PlayerInfo, Players,
+    PHYSICS_TIMESTEP,
+};
+use crate::{GameState, MainCamera};
 
 #[derive(Component)]
 struct Player;
 
+/// Inputs which were predicted locally but not acknowledged by the server yet
+#[derive(Resource, Default)]
+struct PendingInputs {
+    inputs: VecDeque<(u32, PlayerInput)>,
+    next_sequence: u32,
+    last_acked: u32,
+    last_aim: f32,
+}
+
 #[derive(Resource)]
 struct PlayerAssets {
     idle: Handle<TextureAtlas>,
@@ -21,13 +34,21 @@ pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup_player)
-            .add_system_set(SystemSet::on_enter(GameState::Game).with_system(spawn_player_system))
-            .add_system_set(SystemSet::on_update(GameState::Game).with_system(player_shoot_system))
+        app.insert_resource(PendingInputs::default())
+            .add_startup_system(setup_player)
+            .add_system_set(
+                SystemSet::on_update(GameState::Game)
+                    .with_system(spawn_player_system)
+                    .with_system(player_reconciliation_system)
+                    .with_system(player_respawn_system)
+                    .with_system(player_shoot_system),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Game).with_system(despawn_player_system))
+            // A system set only keeps its last run criteria, so these can't also be limited
+            // to the game state. They do nothing without a player anyway.
             .add_system_set(
                 SystemSet::new()
                     .with_run_criteria(FixedTimestep::step(PHYSICS_TIMESTEP))
-                    .with_run_criteria(State::on_update(GameState::Game))
                     .with_system(player_movement_system)
                     .with_system(camera_follow_system),
             );
@@ -48,68 +69,145 @@ fn setup_player(
     commands.insert_resource(player_assets);
 }
 
+/// Spawn the local player once the server told us who we are
 fn spawn_player_system(
     mut commands: Commands,
+    mut events: EventReader<ServerMessage>,
     mut players: ResMut<Players>,
-    client: Res<bevy_renet::renet::RenetClient>,
-    username: Res<MyUsername>,
     player_assets: Res<PlayerAssets>,
 ) {
-    let entity = commands
-        .spawn((
-            SpriteSheetBundle {
-                texture_atlas: player_assets.idle.clone(),
-                ..Default::default()
-            },
-            Player,
-        ))
-        .id();
-
-    players.0.insert(
-        client.client_id(),
-        PlayerInfo {
-            entity,
-            username: username.0.clone(),
-        },
-    );
+    for event in events.iter() {
+        if let ServerMessage::Welcome {
+            id,
+            username,
+            server_name,
+            motd,
+            position,
+            ..
+        } = event
+        {
+            println!("Joined {} as {}", server_name, username);
+            if !motd.is_empty() {
+                println!("{}", motd);
+            }
+
+            commands.insert_resource(PendingInputs::default());
+
+            let entity = commands
+                .spawn((
+                    SpriteSheetBundle {
+                        texture_atlas: player_assets.idle.clone(),
+                        transform: Transform::from_translation(position.extend(0.0)),
+                        ..Default::default()
+                    },
+                    Player,
+                ))
+                .id();
+
+            players.0.insert(*id, PlayerInfo { entity });
+        }
+    }
+}
+
+fn despawn_player_system(mut commands: Commands, query: Query<Entity, With<Player>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    commands.insert_resource(PendingInputs::default());
 }
 
 fn player_movement_system(
-    time: Res<Time>,
     kb: Res<Input<KeyCode>>,
     cursor_pos: Res<CursorWorldPosition>,
     mut query: Query<&mut Transform, With<Player>>,
+    mut pending_inputs: ResMut<PendingInputs>,
     mut events: EventWriter<ClientMessage>,
+    local_health: Res<LocalHealth>,
 ) {
-    if query.is_empty() {
+    if query.is_empty() || local_health.is_down() {
         return;
     }
     let mut transform = query.single_mut();
-    let old_transform = transform.clone();
 
-    // Calculate the direction in which the player will move
-    let direction = Vec2 {
-        x: (kb.pressed(KeyCode::D) as i32 - kb.pressed(KeyCode::A) as i32) as f32,
-        y: (kb.pressed(KeyCode::W) as i32 - kb.pressed(KeyCode::S) as i32) as f32,
+    let diff = cursor_pos.0 - transform.translation.truncate();
+    let input = PlayerInput {
+        up: kb.pressed(KeyCode::W),
+        down: kb.pressed(KeyCode::S),
+        left: kb.pressed(KeyCode::A),
+        right: kb.pressed(KeyCode::D),
+        aim: diff.y.atan2(diff.x),
+    };
+
+    // Only send inputs which change something
+    if input.direction() == Vec2::ZERO && input.aim == pending_inputs.last_aim {
+        return;
     }
-    .normalize_or_zero();
-    let displacement = direction.extend(0.0) * PLAYER_SPEED * time.delta_seconds();
 
-    // Translate the player
-    transform.translation.x += displacement.x;
-    transform.translation.y += displacement.y;
+    // Predict the outcome locally instead of waiting for the server
+    let position = move_player(transform.translation.truncate(), &input);
+    transform.translation = position.extend(transform.translation.z);
+    transform.rotation = Quat::from_rotation_z(input.aim - FRAC_PI_2);
 
-    // Rotate the player
-    let diff = cursor_pos.0 - transform.translation.truncate();
-    let angle = diff.y.atan2(diff.x);
-    transform.rotation = Quat::from_rotation_z(angle - FRAC_PI_2);
-
-    // Send transform update to server if transform has changed
-    if *transform != old_transform {
-        events.send(ClientMessage::PlayerTransformUpdate {
-            position: transform.translation.truncate(),
-            rotation: angle,
-        });
+    pending_inputs.next_sequence += 1;
+    let sequence = pending_inputs.next_sequence;
+    pending_inputs.inputs.push_back((sequence, input));
+    pending_inputs.last_aim = input.aim;
+
+    let skip = pending_inputs
+        .inputs
+        .len()
+        .saturating_sub(MAX_INPUTS_PER_MESSAGE);
+    events.send(ClientMessage::PlayerInput {
+        inputs: pending_inputs.inputs.iter().skip(skip).copied().collect(),
+    });
+}
+
+/// Snap to the server's position and replay the inputs it hasn't processed yet
+fn player_reconciliation_system(
+    mut events: EventReader<ServerMessage>,
+    mut query: Query<&mut Transform, With<Player>>,
+    mut pending_inputs: ResMut<PendingInputs>,
+) {
+    for event in events.iter() {
+        if let ServerMessage::PlayerInputAck { sequence, position } = event {
+            // Acks travel unreliably, so an older one may arrive late
+            if *sequence <= pending_inputs.last_acked {
+                continue;
+            }
+            pending_inputs.last_acked = *sequence;
+            pending_inputs
+                .inputs
+                .retain(|(pending_sequence, _)| pending_sequence > sequence);
+
+            if let Ok(mut transform) = query.get_single_mut() {
+                let predicted = pending_inputs
+                    .inputs
+                    .iter()
+                    .fold(*position, |position, (_, input)| {
+                        move_player(position, input)
+                    });
+
+                transform.translation = predicted.extend(transform.translation.z);
+            }
+        }
+    }
+}
+
+/// The server moved the player back to the spawn, inputs from before no longer apply
+fn player_respawn_system(
+    mut events: EventReader<ServerMessage>,
+    mut query: Query<&mut Transform, With<Player>>,
+    mut pending_inputs: ResMut<PendingInputs>,
+) {
+    for event in events.iter() {
+        if let ServerMessage::PlayerRespawned { position } = event {
+            pending_inputs.inputs.clear();
+
+            if let Ok(mut transform) = query.get_single_mut() {
+                transform.translation = position.extend(transform.translation.z);
+            }
+        }
     }
 }
 
@@ -138,15 +236,24 @@ fn player_shoot_system(
     mut events: EventWriter<ClientMessage>,
     mouse: Res<Input<MouseButton>>,
     cursor_pos: Res<CursorWorldPosition>,
+    render_clock: Res<RenderClock>,
+    local_health: Res<LocalHealth>,
     query: Query<&Transform, With<Player>>,
 ) {
-    if !mouse.just_pressed(MouseButton::Left) {
+    if !mouse.just_pressed(MouseButton::Left) || local_health.is_down() {
         return;
     }
 
-    let player_tf = query.single();
+    // The local player only exists once the server welcomed us
+    let Ok(player_tf) = query.get_single() else {
+        return;
+    };
     let diff = cursor_pos.0 - player_tf.translation.truncate();
     let direction = diff.y.atan2(diff.x);
 
-    events.send(ClientMessage::Shoot { direction });
+    // The server checks for hits where the targets were on our screen
+    events.send(ClientMessage::Shoot {
+        direction,
+        tick: render_clock.tick.round().max(0.0) as u64,
+    });
 }
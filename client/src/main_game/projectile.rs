@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use super::interpolation::RenderClock;
+use crate::GameState;
+use shared::*;
+
+const PROJECTILE_SIZE: Vec2 = Vec2::new(16.0, 4.0);
+const PROJECTILE_COLOR: Color = Color::rgb(1.0, 0.85, 0.3);
+
+/// Flies in a straight line on the render clock's timeline, so it lines up with the
+/// interpolated entities it may hit
+#[derive(Component)]
+struct Projectile {
+    id: u64,
+    origin: Vec2,
+    velocity: Vec2,
+    /// Server tick at which the projectile is at `origin`
+    start_tick: u64,
+    /// Server tick at which it stops existing
+    end_tick: f64,
+}
+
+/// End ticks of projectiles whose despawn arrived in the same frame as their spawn, keyed
+/// by id. The spawn commands haven't been applied yet, so there is no entity to update.
+#[derive(Resource, Default)]
+struct PendingEndTicks(HashMap<u64, u64>);
+
+pub struct ProjectilePlugin;
+
+impl Plugin for ProjectilePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(PendingEndTicks::default())
+            .add_system_set(
+                SystemSet::on_update(GameState::Game)
+                    .with_system(spawn_projectile_system.after(despawn_projectile_system))
+                    .with_system(despawn_projectile_system)
+                    .with_system(projectile_movement_system),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameState::Game).with_system(despawn_all_projectiles_system),
+            );
+    }
+}
+
+fn spawn_projectile_system(
+    mut commands: Commands,
+    mut events: EventReader<ServerMessage>,
+    mut pending_end_ticks: ResMut<PendingEndTicks>,
+    clock: Res<RenderClock>,
+) {
+    for event in events.iter() {
+        if let ServerMessage::SpawnProjectile {
+            id,
+            tick,
+            position,
+            direction,
+            ..
+        } = event
+        {
+            let lifetime_end = *tick as f64 + PROJECTILE_LIFETIME as f64 * clock.tick_rate;
+            let end_tick = match pending_end_ticks.0.remove(id) {
+                Some(end_tick) => lifetime_end.min(end_tick as f64),
+                None => lifetime_end,
+            };
+
+            commands.spawn((
+                SpriteBundle {
+                    sprite: Sprite {
+                        color: PROJECTILE_COLOR,
+                        custom_size: Some(PROJECTILE_SIZE),
+                        ..Default::default()
+                    },
+                    transform: Transform {
+                        translation: position.extend(1.0),
+                        rotation: Quat::from_rotation_z(*direction),
+                        ..Default::default()
+                    },
+                    // Until the render clock reaches the tick it was fired at
+                    visibility: Visibility { is_visible: false },
+                    ..Default::default()
+                },
+                Projectile {
+                    id: *id,
+                    origin: *position,
+                    velocity: Vec2::from_angle(*direction) * PROJECTILE_SPEED,
+                    start_tick: *tick,
+                    end_tick,
+                },
+            ));
+        }
+    }
+}
+
+fn despawn_projectile_system(
+    mut events: EventReader<ServerMessage>,
+    mut pending_end_ticks: ResMut<PendingEndTicks>,
+    mut query: Query<&mut Projectile>,
+) {
+    for event in events.iter() {
+        if let ServerMessage::DespawnProjectile { id, tick } = event {
+            match query.iter_mut().find(|projectile| projectile.id == *id) {
+                Some(mut projectile) => {
+                    projectile.end_tick = projectile.end_tick.min(*tick as f64);
+                }
+                // Spawned in this frame, which happens after this system
+                None => {
+                    pending_end_ticks.0.insert(*id, *tick);
+                }
+            }
+        }
+    }
+}
+
+fn projectile_movement_system(
+    mut commands: Commands,
+    clock: Res<RenderClock>,
+    mut query: Query<(Entity, &Projectile, &mut Transform, &mut Visibility)>,
+) {
+    for (entity, projectile, mut transform, mut visibility) in query.iter_mut() {
+        if clock.tick >= projectile.end_tick {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let elapsed_ticks = clock.tick - projectile.start_tick as f64;
+        visibility.is_visible = elapsed_ticks >= 0.0;
+
+        let elapsed = (elapsed_ticks.max(0.0) / clock.tick_rate) as f32;
+        let position = projectile.origin + projectile.velocity * elapsed;
+        transform.translation = position.extend(transform.translation.z);
+    }
+}
+
+fn despawn_all_projectiles_system(
+    mut commands: Commands,
+    mut pending_end_ticks: ResMut<PendingEndTicks>,
+    query: Query<Entity, With<Projectile>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+    pending_end_ticks.0.clear();
+}
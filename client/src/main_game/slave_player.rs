@@ -2,9 +2,7 @@ use std::f32::consts::FRAC_PI_2;
 
 use bevy::prelude::*;
 
-use shared::*;
-
-use super::{PlayerInfo, Players};
+use super::{health::Health, interpolation::Interpolated, PlayerInfo, Players};
 use crate::{GameState, UIAssets};
 
 pub mod events {
@@ -14,6 +12,9 @@ pub mod events {
         pub id: u64,
         pub username: String,
         pub position: Vec2,
+        /// Aim angle, like in state snapshots
+        pub rotation: f32,
+        pub health: f32,
     }
 
     pub struct DespawnSlavePlayer {
@@ -47,7 +48,10 @@ impl Plugin for SlavePlayerPlugin {
                 SystemSet::on_update(GameState::Game)
                     .with_system(spawn_slave_player_system)
                     .with_system(despawn_slave_player_system)
-                    .with_system(transform_slave_player_system),
+                    .with_system(username_label_system),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameState::Game).with_system(despawn_all_slave_players_system),
             );
     }
 }
@@ -74,6 +78,11 @@ fn spawn_slave_player_system(
     ui_assets: Res<UIAssets>,
 ) {
     for event in events.iter() {
+        // Keep the entity of anything which is spawned already
+        if players.0.contains_key(&event.id) {
+            continue;
+        }
+
         // Username Label
         let username_entity = commands
             .spawn((
@@ -89,6 +98,9 @@ fn spawn_slave_player_system(
                         )],
                         alignment: TextAlignment::CENTER,
                     },
+                    transform: Transform::from_translation(
+                        event.position.extend(0.0) + USERNAME_LABEL_OFFSET,
+                    ),
                     ..Default::default()
                 },
                 UsernameLabel,
@@ -101,11 +113,14 @@ fn spawn_slave_player_system(
                     texture_atlas: slave_player_assets.idle.clone(),
                     transform: Transform {
                         translation: event.position.extend(0.0),
+                        rotation: Quat::from_rotation_z(event.rotation - FRAC_PI_2),
                         ..Default::default()
                     },
                     ..Default::default()
                 },
                 SlavePlayer { username_entity },
+                Interpolated::default(),
+                Health(event.health),
             ))
             .id();
 
@@ -113,7 +128,6 @@ fn spawn_slave_player_system(
             event.id,
             PlayerInfo {
                 entity: player_entity,
-                username: event.username.clone(),
             },
         );
     }
@@ -135,30 +149,23 @@ fn despawn_slave_player_system(
     }
 }
 
-fn transform_slave_player_system(
-    mut events: EventReader<ServerMessage>,
-    mut slave_query: Query<(&mut Transform, &SlavePlayer)>,
+/// Slave players along with their username labels
+type SlavePlayerEntities<'w, 's> =
+    Query<'w, 's, Entity, Or<(With<SlavePlayer>, With<UsernameLabel>)>>;
+
+fn despawn_all_slave_players_system(mut commands: Commands, query: SlavePlayerEntities) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
+fn username_label_system(
+    slave_query: Query<(&Transform, &SlavePlayer), Changed<Transform>>,
     mut username_query: Query<&mut Transform, (With<UsernameLabel>, Without<SlavePlayer>)>,
-    players: Res<Players>,
 ) {
-    for server_msg in events.iter() {
-        if let ServerMessage::PlayerTransformUpdate {
-            id,
-            position,
-            rotation,
-        } = server_msg
-        {
-            if let Some(info) = players.0.get(id) {
-                if let Ok((mut player_tf, player)) = slave_query.get_mut(info.entity) {
-                    let angle = *rotation - FRAC_PI_2;
-
-                    player_tf.translation = position.extend(0.0);
-                    player_tf.rotation = Quat::from_rotation_z(angle);
-
-                    let mut username_tf = username_query.get_mut(player.username_entity).unwrap();
-                    username_tf.translation = player_tf.translation + USERNAME_LABEL_OFFSET;
-                }
-            }
+    for (player_tf, player) in slave_query.iter() {
+        if let Ok(mut username_tf) = username_query.get_mut(player.username_entity) {
+            username_tf.translation = player_tf.translation + USERNAME_LABEL_OFFSET;
         }
     }
 }
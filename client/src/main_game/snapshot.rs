@@ -0,0 +1,125 @@
+use std::{collections::VecDeque, f32::consts::FRAC_PI_2};
+
+use bevy::prelude::*;
+
+use super::{
+    health::Health,
+    interpolation::{Interpolated, RenderClock},
+    Orcs, Players,
+};
+use crate::GameState;
+use shared::{
+    snapshot::{apply_delta, NetId, WorldState},
+    *,
+};
+
+/// Matches the history the server keeps for delta encoding
+const SNAPSHOT_HISTORY: usize = 32;
+
+/// Full states rebuilt from received snapshots, the baselines for upcoming deltas
+#[derive(Resource, Default)]
+struct ReceivedSnapshots(VecDeque<(u64, WorldState)>);
+
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ReceivedSnapshots::default())
+            .add_system_set(
+                SystemSet::on_update(GameState::Game).with_system(state_snapshot_system),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Game).with_system(reset_snapshots));
+    }
+}
+
+fn reset_snapshots(mut snapshots: ResMut<ReceivedSnapshots>) {
+    snapshots.0.clear();
+}
+
+#[allow(clippy::too_many_arguments)]
+fn state_snapshot_system(
+    mut events: EventReader<ServerMessage>,
+    mut client_msg_events: EventWriter<ClientMessage>,
+    mut snapshots: ResMut<ReceivedSnapshots>,
+    mut clock: ResMut<RenderClock>,
+    mut query: Query<&mut Interpolated>,
+    mut health_query: Query<&mut Health>,
+    players: Res<Players>,
+    orcs: Res<Orcs>,
+) {
+    for event in events.iter() {
+        let (tick, baseline, updates, removed) = match event {
+            ServerMessage::Welcome { .. } => {
+                // Baselines from an earlier session mean nothing to this server
+                snapshots.0.clear();
+                continue;
+            }
+            ServerMessage::StateSnapshot {
+                tick,
+                baseline,
+                updates,
+                removed,
+            } => (*tick, *baseline, updates, removed),
+            _ => continue,
+        };
+
+        // Snapshots travel unreliably, so older ones may arrive late
+        if snapshots
+            .0
+            .back()
+            .is_some_and(|(newest, _)| tick <= *newest)
+        {
+            continue;
+        }
+
+        let baseline_state = match baseline {
+            Some(baseline) => match snapshots.0.iter().find(|(t, _)| *t == baseline) {
+                Some((_, state)) => Some(state),
+                // Already dropped, the server falls back to a full snapshot soon
+                None => continue,
+            },
+            None => None,
+        };
+
+        let state = match apply_delta(baseline_state, updates, removed) {
+            Some(state) => state,
+            None => continue,
+        };
+
+        clock.observe(tick);
+
+        for (id, entity_state) in state.iter() {
+            let entity = match id {
+                NetId::Player(id) => players.0.get(id).map(|info| info.entity),
+                NetId::Orc(id) => orcs.0.get(id).copied(),
+            };
+
+            let entity = match entity {
+                Some(entity) => entity,
+                None => continue,
+            };
+
+            if let Ok(mut interpolated) = query.get_mut(entity) {
+                // Sprites face up, so a rotation of 0 should point them right
+                interpolated.push(
+                    tick,
+                    entity_state.position,
+                    entity_state.rotation - FRAC_PI_2,
+                );
+            }
+
+            if let Ok(mut health) = health_query.get_mut(entity) {
+                if health.0 != entity_state.health {
+                    health.0 = entity_state.health;
+                }
+            }
+        }
+
+        client_msg_events.send(ClientMessage::SnapshotAck { tick });
+
+        snapshots.0.push_back((tick, state));
+        if snapshots.0.len() > SNAPSHOT_HISTORY {
+            snapshots.0.pop_front();
+        }
+    }
+}
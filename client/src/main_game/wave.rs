@@ -0,0 +1,129 @@
+use bevy::prelude::*;
+
+use crate::{GameState, UIAssets};
+use shared::*;
+
+/// What the server last said about the waves
+#[derive(Resource, Default)]
+enum WaveStatus {
+    #[default]
+    Unknown,
+    Active {
+        wave: u32,
+        orcs: u32,
+    },
+    Intermission {
+        wave: u32,
+        /// Elapsed time at which the next wave starts
+        next_wave_at: f64,
+    },
+}
+
+#[derive(Component)]
+struct WaveHud;
+
+#[derive(Component)]
+struct WaveText;
+
+pub struct WavePlugin;
+
+impl Plugin for WavePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(WaveStatus::default())
+            .add_system_set(SystemSet::on_enter(GameState::Game).with_system(setup_wave_hud))
+            .add_system_set(
+                SystemSet::on_update(GameState::Game)
+                    .with_system(wave_message_system)
+                    .with_system(wave_text_system.after(wave_message_system)),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Game).with_system(destroy_wave_hud));
+    }
+}
+
+fn setup_wave_hud(mut commands: Commands, ui_assets: Res<UIAssets>) {
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    justify_content: JustifyContent::Center,
+                    size: Size::new(Val::Percent(100.), Val::Auto),
+                    padding: UiRect::all(Val::Px(10.)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            WaveHud,
+        ))
+        .with_children(|node| {
+            node.spawn((
+                TextBundle::from_section(
+                    String::new(),
+                    TextStyle {
+                        font_size: 24.0,
+                        color: Color::WHITE,
+                        font: ui_assets.font.clone(),
+                    },
+                ),
+                WaveText,
+            ));
+        });
+}
+
+fn destroy_wave_hud(
+    mut commands: Commands,
+    mut status: ResMut<WaveStatus>,
+    query: Query<Entity, With<WaveHud>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    *status = WaveStatus::default();
+}
+
+fn wave_message_system(
+    time: Res<Time>,
+    mut server_msg_events: EventReader<ServerMessage>,
+    mut status: ResMut<WaveStatus>,
+) {
+    for server_msg in server_msg_events.iter() {
+        match server_msg {
+            ServerMessage::WaveStarted { wave, orcs } => {
+                *status = WaveStatus::Active {
+                    wave: *wave,
+                    orcs: *orcs,
+                };
+            }
+            ServerMessage::WaveEnded { wave, next_wave_in } => {
+                *status = WaveStatus::Intermission {
+                    wave: *wave,
+                    next_wave_at: time.elapsed_seconds_f64() + *next_wave_in as f64,
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+fn wave_text_system(
+    time: Res<Time>,
+    status: Res<WaveStatus>,
+    mut query: Query<&mut Text, With<WaveText>>,
+) {
+    let text_value = match *status {
+        WaveStatus::Unknown => String::new(),
+        WaveStatus::Active { wave, orcs } => format!("Wave {} - {} orcs incoming", wave, orcs),
+        WaveStatus::Intermission { wave, next_wave_at } => {
+            let seconds_left = (next_wave_at - time.elapsed_seconds_f64()).max(0.0).ceil();
+            if wave == 0 {
+                format!("First wave in {:.0}s", seconds_left)
+            } else {
+                format!("Wave {} cleared! Next wave in {:.0}s", wave, seconds_left)
+            }
+        }
+    };
+
+    for mut text in query.iter_mut() {
+        text.sections[0].value = text_value.clone();
+    }
+}
@@ -1,14 +1,21 @@
 use std::{
     error::Error,
-    net::{SocketAddr, UdpSocket},
+    fs::File,
+    io::BufReader,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    path::{Path, PathBuf},
     time::SystemTime,
 };
 
 use bevy::{app::AppExit, prelude::*};
-use bevy_renet::{renet::NETCODE_USER_DATA_BYTES, *};
+use bevy_renet::*;
 
-use crate::{GameState, UIAssets};
-use shared::*;
+use crate::{ConnectTokenFile, ConnectionError, GameState, ResumeToken, UIAssets};
+use shared::{
+    auth::{read_token_file, serialize_user_data},
+    username::validate_username,
+    *,
+};
 
 const BUTTON_MARGIN: UiRect = UiRect {
     top: Val::Px(10.0),
@@ -31,67 +38,212 @@ enum Button {
     Quit,
 }
 
+const FIELD_COLOR: Color = Color::rgb(0.48, 0.48, 0.48);
+const FOCUSED_FIELD_COLOR: Color = Color::rgb(0.62, 0.62, 0.62);
+
+#[derive(Component, Clone, Copy, PartialEq, Eq)]
+enum TextField {
+    Username,
+    ServerAddress,
+}
+
+impl TextField {
+    fn placeholder(&self) -> &'static str {
+        match self {
+            TextField::Username => "Enter A Username",
+            TextField::ServerAddress => "Enter Server Address",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            TextField::Username => "Username",
+            TextField::ServerAddress => "Server",
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            TextField::Username => TextField::ServerAddress,
+            TextField::ServerAddress => TextField::Username,
+        }
+    }
+}
+
 #[derive(Component)]
-struct UsernameText;
+struct FieldText(TextField);
+
+#[derive(Component)]
+struct ErrorText;
+
+#[derive(Resource)]
+struct FocusedField(TextField);
 
 #[derive(Resource)]
 pub struct MyUsername(pub String);
 
+#[derive(Resource)]
+pub struct MyServerAddress(pub String);
+
+/// Where the last connection attempt went, so it can be retried
+#[derive(Resource, Clone)]
+pub enum ConnectTarget {
+    Unsecure {
+        server_addr: SocketAddr,
+        username: String,
+    },
+    Secure {
+        token_path: PathBuf,
+    },
+}
+
+impl ConnectTarget {
+    /// The user data of connect tokens is sealed, so only unsecure connections can resume
+    /// a session
+    pub fn create_client(
+        &self,
+        resume_token: Option<u64>,
+    ) -> Result<renet::RenetClient, Box<dyn Error>> {
+        match self {
+            ConnectTarget::Unsecure {
+                server_addr,
+                username,
+            } => create_renet_client(
+                *server_addr,
+                UserData {
+                    resume_token,
+                    ..UserData::new(username.clone())
+                },
+            ),
+            ConnectTarget::Secure { token_path } => create_secure_renet_client(token_path),
+        }
+    }
+
+    pub fn can_resume(&self) -> bool {
+        matches!(self, ConnectTarget::Unsecure { .. })
+    }
+
+    /// The server only accepts a connect token from the address it was first used from, and
+    /// every attempt binds a new socket. A secure connection needs a fresh token instead.
+    pub fn can_retry(&self) -> bool {
+        matches!(self, ConnectTarget::Unsecure { .. })
+    }
+}
+
 pub struct MainMenuPlugin;
 
 impl Plugin for MainMenuPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(MyUsername(String::new()))
+            .insert_resource(MyServerAddress(format!(
+                "127.0.0.1:{}",
+                DEFAULT_SERVER_PORT
+            )))
+            .insert_resource(FocusedField(TextField::Username))
             .add_system_set(SystemSet::on_enter(GameState::MainMenu).with_system(setup_main_menu))
             .add_system_set(SystemSet::on_exit(GameState::MainMenu).with_system(destroy_main_menu))
             .add_system_set(
                 SystemSet::on_update(GameState::MainMenu)
-                    .with_system(handle_username_input)
+                    .with_system(handle_field_focus)
+                    .with_system(handle_text_input)
                     .with_system(handle_buttons),
             );
     }
 }
 
-fn create_renet_client(user_data: UserData) -> Result<renet::RenetClient, Box<dyn Error>> {
-    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+/// Resolve a user entered server address. Accepts IPv4, IPv6 (`[::1]:5678`) and
+/// host names, falling back to the default port when none is given.
+fn resolve_server_addr(input: &str) -> Result<SocketAddr, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("Server address cannot be empty".to_owned());
+    }
 
-    // TODO: Implement entering a custom server address
-    let server_addr: SocketAddr = "127.0.0.1:5678".parse()?;
-    let socket = UdpSocket::bind("127.0.0.1:0")?;
+    // Full socket address, like 127.0.0.1:5678 or [::1]:5678
+    if let Ok(addr) = input.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
 
-    let config = renet::RenetConnectionConfig::default();
+    // Bare IP address without a port, like ::1 or 192.168.0.2
+    let bare_ip = input.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = bare_ip.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, DEFAULT_SERVER_PORT));
+    }
 
-    let user_data_serialized = {
-        // Check whether the serialized data would be small enough
-        if bincode::serialized_size::<UserData>(&user_data)? as usize > NETCODE_USER_DATA_BYTES {
-            return Err(format!(
-                "User data is too large (>{} bytes)",
-                NETCODE_USER_DATA_BYTES
-            )
-            .into());
+    // Host name with an optional port
+    let (host, port) = match input.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port \"{}\"", port))?;
+            (host, port)
         }
+        None => (input, DEFAULT_SERVER_PORT),
+    };
 
-        let user_data = bincode::serialize(&user_data)?;
-        let mut data_array: [u8; 256] = [0; NETCODE_USER_DATA_BYTES];
+    if host.is_empty() {
+        return Err("Server address is missing a host".to_owned());
+    }
 
-        for i in 0..user_data.len() {
-            data_array[i] = user_data[i];
-        }
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Could not resolve \"{}\": {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("\"{}\" did not resolve to any address", host))
+}
 
-        data_array
+/// Pick a local address on the same interface family as the server.
+fn local_bind_addr(server_addr: &SocketAddr) -> SocketAddr {
+    let ip = match server_addr.ip() {
+        IpAddr::V4(ip) if ip.is_loopback() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(ip) if ip.is_loopback() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
     };
 
+    SocketAddr::new(ip, 0)
+}
+
+fn create_renet_client(
+    server_addr: SocketAddr,
+    user_data: UserData,
+) -> Result<renet::RenetClient, Box<dyn Error>> {
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let socket = UdpSocket::bind(local_bind_addr(&server_addr))?;
+
+    let config = channels::connection_config();
+
     let authentication = renet::ClientAuthentication::Unsecure {
         protocol_id: PROTOCOL_ID,
-        client_id: current_time.as_millis() as u64,
+        client_id: rand::random(),
         server_addr,
-        user_data: Some(user_data_serialized),
+        user_data: Some(serialize_user_data(&user_data)?),
     };
 
     renet::RenetClient::new(current_time, socket, config, authentication).map_err(|e| e.into())
 }
 
-fn setup_main_menu(mut commands: Commands, ui_assets: Res<UIAssets>) {
+/// Connect using a token issued by the server. The server address, client id and
+/// user data all come from the token.
+fn create_secure_renet_client(token_path: &Path) -> Result<renet::RenetClient, Box<dyn Error>> {
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+
+    let file = File::open(token_path)
+        .map_err(|e| format!("Could not open {}: {}", token_path.display(), e))?;
+    let (server_addr, connect_token) = read_token_file(&mut BufReader::new(file))?;
+
+    let socket = UdpSocket::bind(local_bind_addr(&server_addr))?;
+    let config = channels::connection_config();
+    let authentication = renet::ClientAuthentication::Secure { connect_token };
+
+    renet::RenetClient::new(current_time, socket, config, authentication).map_err(|e| e.into())
+}
+
+fn setup_main_menu(
+    mut commands: Commands,
+    mut connection_error: ResMut<ConnectionError>,
+    ui_assets: Res<UIAssets>,
+) {
     commands
         .spawn((
             NodeBundle {
@@ -144,34 +296,63 @@ fn setup_main_menu(mut commands: Commands, ui_assets: Res<UIAssets>) {
                 ..Default::default()
             })
             .with_children(|center_node| {
-                // Username "Field"
+                // Text Fields
                 center_node
                     .spawn(NodeBundle {
-                        background_color: BackgroundColor(Color::rgb(0.48, 0.48, 0.48)),
                         style: Style {
-                            border: UiRect::all(Val::Px(5.0)),
-                            padding: BUTTON_MARGIN,
-                            margin: BUTTON_MARGIN,
-                            min_size: BUTTON_MIN_SIZE,
                             align_items: AlignItems::Center,
+                            flex_direction: FlexDirection::Row,
                             ..Default::default()
                         },
                         ..Default::default()
                     })
-                    .with_children(|field_node| {
-                        field_node.spawn((
-                            TextBundle::from_section(
-                                "Enter A Username",
-                                TextStyle {
-                                    font_size: 25.0,
-                                    color: Color::WHITE,
-                                    font: ui_assets.font.clone(),
-                                },
-                            ),
-                            UsernameText,
-                        ));
+                    .with_children(|row_node| {
+                        for field in [TextField::Username, TextField::ServerAddress] {
+                            row_node
+                                .spawn((
+                                    ButtonBundle {
+                                        background_color: BackgroundColor(FIELD_COLOR),
+                                        style: Style {
+                                            border: UiRect::all(Val::Px(5.0)),
+                                            padding: BUTTON_MARGIN,
+                                            margin: BUTTON_MARGIN,
+                                            min_size: BUTTON_MIN_SIZE,
+                                            align_items: AlignItems::Center,
+                                            ..Default::default()
+                                        },
+                                        ..Default::default()
+                                    },
+                                    field,
+                                ))
+                                .with_children(|field_node| {
+                                    field_node.spawn((
+                                        TextBundle::from_section(
+                                            field.placeholder(),
+                                            TextStyle {
+                                                font_size: 25.0,
+                                                color: Color::WHITE,
+                                                font: ui_assets.font.clone(),
+                                            },
+                                        ),
+                                        FieldText(field),
+                                    ));
+                                });
+                        }
                     });
 
+                // Error Text
+                center_node.spawn((
+                    TextBundle::from_section(
+                        connection_error.0.take().unwrap_or_default(),
+                        TextStyle {
+                            font_size: 20.0,
+                            color: Color::ORANGE_RED,
+                            font: ui_assets.font.clone(),
+                        },
+                    ),
+                    ErrorText,
+                ));
+
                 // Connect Button
                 center_node
                     .spawn((
@@ -190,7 +371,7 @@ fn setup_main_menu(mut commands: Commands, ui_assets: Res<UIAssets>) {
                     ))
                     .with_children(|button| {
                         button.spawn(TextBundle::from_section(
-                            "Connect to Localhost",
+                            "Connect",
                             TextStyle {
                                 font_size: 20.0,
                                 color: Color::WHITE,
@@ -235,39 +416,78 @@ fn destroy_main_menu(mut commands: Commands, query: Query<Entity, With<MainMenu>
     }
 }
 
-fn handle_username_input(
+fn handle_field_focus(
+    mut focused: ResMut<FocusedField>,
+    mut query: Query<(&TextField, &Interaction, &mut BackgroundColor)>,
+    kb: Res<Input<KeyCode>>,
+) {
+    if kb.just_pressed(KeyCode::Tab) {
+        focused.0 = focused.0.next();
+    }
+
+    for (field, interaction, _) in query.iter() {
+        if *interaction == Interaction::Clicked {
+            focused.0 = *field;
+        }
+    }
+
+    for (field, _, mut color) in query.iter_mut() {
+        color.0 = if *field == focused.0 {
+            FOCUSED_FIELD_COLOR
+        } else {
+            FIELD_COLOR
+        };
+    }
+}
+
+fn handle_text_input(
     mut events: EventReader<ReceivedCharacter>,
-    mut query: Query<&mut Text, With<UsernameText>>,
+    mut query: Query<(&mut Text, &FieldText)>,
     mut username: ResMut<MyUsername>,
+    mut server_address: ResMut<MyServerAddress>,
+    focused: Res<FocusedField>,
     kb: Res<Input<KeyCode>>,
 ) {
+    let input = match focused.0 {
+        TextField::Username => &mut username.0,
+        TextField::ServerAddress => &mut server_address.0,
+    };
+
     for received in events.iter() {
         let ch = received.char;
         if !(ch.is_control() || ch.is_whitespace()) {
-            username.0.push(received.char);
+            input.push(ch);
         }
     }
 
     if kb.just_pressed(KeyCode::Back) {
-        username.0.pop();
+        input.pop();
     }
 
-    let mut username_text = query.single_mut();
-    let mut section = &mut username_text.sections[0];
-
-    if username.0.is_empty() {
-        section.value = "Enter A Username".to_owned();
-    } else {
-        // Only copy the username if it's changed
-        section.value = format!("Username: {}", username.0);
+    for (mut text, FieldText(field)) in query.iter_mut() {
+        let value = match field {
+            TextField::Username => &username.0,
+            TextField::ServerAddress => &server_address.0,
+        };
+
+        let section = &mut text.sections[0];
+        if value.is_empty() {
+            section.value = field.placeholder().to_owned();
+        } else {
+            section.value = format!("{}: {}", field.label(), value);
+        }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_buttons(
     mut commands: Commands,
     mut game_state: ResMut<State<GameState>>,
     mut exit: EventWriter<AppExit>,
+    mut error_query: Query<&mut Text, With<ErrorText>>,
     username: Res<MyUsername>,
+    server_address: Res<MyServerAddress>,
+    connect_token: Res<ConnectTokenFile>,
     query: Query<(&Button, &Interaction), Changed<Interaction>>,
 ) {
     for (btn, interaction) in query.iter() {
@@ -277,23 +497,44 @@ fn handle_buttons(
 
         match *btn {
             Button::Connect => {
-                // Only connect if username is not empty
-                if username.0.is_empty() {
-                    break;
-                }
+                let mut error_text = error_query.single_mut();
 
-                let client = create_renet_client(UserData {
-                    username: username.0.trim().to_owned(),
-                });
+                // The username of a secure connection is sealed in the token
+                let target = if let Some(token_path) = &connect_token.0 {
+                    ConnectTarget::Secure {
+                        token_path: token_path.clone(),
+                    }
+                } else {
+                    if let Err(e) = validate_username(username.0.trim()) {
+                        error_text.sections[0].value = e;
+                        break;
+                    }
+
+                    let server_addr = match resolve_server_addr(&server_address.0) {
+                        Ok(addr) => addr,
+                        Err(e) => {
+                            error_text.sections[0].value = e;
+                            break;
+                        }
+                    };
+
+                    ConnectTarget::Unsecure {
+                        server_addr,
+                        username: username.0.trim().to_owned(),
+                    }
+                };
 
-                match client {
+                // A new connection starts a new session
+                match target.create_client(None) {
                     Ok(client) => {
+                        error_text.sections[0].value.clear();
                         commands.insert_resource(client);
+                        commands.insert_resource(target);
+                        commands.insert_resource(ResumeToken(None));
                         let _ = game_state.set(GameState::Connecting);
                     }
                     Err(e) => {
-                        // TODO: Display error message in GUI.
-                        eprintln!("{e:?}");
+                        error_text.sections[0].value = format!("Could not connect: {}", e);
                     }
                 };
             }
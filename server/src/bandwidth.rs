@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{config::ServerSettings, Players};
+
+/// Seconds over which `bytes_per_second` is averaged
+const STATS_WINDOW: f64 = 1.0;
+
+/// Traffic sent to a single client
+#[derive(Debug, Default)]
+pub struct ClientBandwidth {
+    pub total_bytes: u64,
+    /// Average over the last full stats window
+    pub bytes_per_second: f64,
+    /// State updates which didn't fit into the budget of the last snapshot
+    pub deferred_updates: usize,
+    window_bytes: u64,
+}
+
+impl ClientBandwidth {
+    pub fn record(&mut self, bytes: usize) {
+        self.total_bytes += bytes as u64;
+        self.window_bytes += bytes as u64;
+    }
+}
+
+/// Bandwidth statistics keyed by client id
+#[derive(Resource, Default)]
+pub struct BandwidthStats(pub HashMap<u64, ClientBandwidth>);
+
+pub struct BandwidthPlugin;
+
+impl Plugin for BandwidthPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(BandwidthStats::default())
+            .add_system(bandwidth_stats_system);
+    }
+}
+
+fn bandwidth_stats_system(
+    mut window_start: Local<f64>,
+    mut last_report: Local<f64>,
+    time: Res<Time>,
+    mut stats: ResMut<BandwidthStats>,
+    players: Res<Players>,
+    settings: Res<ServerSettings>,
+) {
+    let now = time.elapsed_seconds_f64();
+    let elapsed = now - *window_start;
+    if elapsed < STATS_WINDOW {
+        return;
+    }
+    *window_start = now;
+
+    // Forget clients which left, as well as rejected ones which never joined
+    stats
+        .0
+        .retain(|client_id, _| players.0.contains_key(client_id));
+
+    for client in stats.0.values_mut() {
+        client.bytes_per_second = client.window_bytes as f64 / elapsed;
+        client.window_bytes = 0;
+    }
+
+    let report_interval = match settings.bandwidth_report_interval {
+        Some(report_interval) => report_interval,
+        None => return,
+    };
+    if now - *last_report < report_interval {
+        return;
+    }
+    *last_report = now;
+
+    for (client_id, player_info) in players.0.iter() {
+        if let Some(client) = stats.0.get(client_id) {
+            println!(
+                "{} ({}): {:.1} kB/s, {:.1} kB total, {} updates deferred",
+                client_id,
+                player_info.username,
+                client.bytes_per_second / 1000.0,
+                client.total_bytes as f64 / 1000.0,
+                client.deferred_updates
+            );
+        }
+    }
+}
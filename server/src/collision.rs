@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{
+    components::Velocity, config::ServerSettings, player::PLAYER_RADIUS,
+    projectile::segment_distance,
+};
+use shared::WORLD_HALF_EXTENT;
+
+pub mod events {
+    use bevy::prelude::Entity;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CollisionKind {
+        /// `a` is a projectile which touched orc `b`
+        ProjectileOrc,
+        /// `a` is an orc which bumped into player `b`
+        OrcPlayer,
+        /// `a` ran into wall `b`
+        Wall,
+    }
+
+    pub struct Collision {
+        pub kind: CollisionKind,
+        pub a: Entity,
+        pub b: Entity,
+    }
+}
+
+use events::{Collision, CollisionKind};
+
+/// Side length of a spatial hash cell, about twice the size of a player or an orc
+const CELL_SIZE: f32 = 64.0;
+const WALL_THICKNESS: f32 = 512.0;
+/// Distance from the origin to the inside of the walls around the world. Movement keeps
+/// players within the world, so they never touch the walls and mispredict.
+const WALL_DISTANCE: f32 = WORLD_HALF_EXTENT + PLAYER_RADIUS;
+
+#[derive(Component, Debug, Clone, Copy)]
+pub enum Collider {
+    Circle { radius: f32 },
+    Aabb { half_extents: Vec2 },
+}
+
+/// What an entity collides as, which decides who it collides with and who gets pushed
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionLayer {
+    Player,
+    Orc,
+    /// Touches orcs and walls, but never gets pushed
+    Projectile,
+    /// Never moves
+    Wall,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Body {
+    entity: Entity,
+    layer: CollisionLayer,
+    collider: Collider,
+    position: Vec2,
+    /// Position at the start of the tick, so fast bodies can't skip past others
+    start: Vec2,
+}
+
+impl Body {
+    fn bounds(&self) -> (Vec2, Vec2) {
+        let half_extents = match self.collider {
+            Collider::Circle { radius } => Vec2::splat(radius),
+            Collider::Aabb { half_extents } => half_extents,
+        };
+
+        (
+            self.start.min(self.position) - half_extents,
+            self.start.max(self.position) + half_extents,
+        )
+    }
+}
+
+/// Overlap of two bodies
+struct Contact {
+    /// Points from the first body to the second
+    normal: Vec2,
+    depth: f32,
+}
+
+/// How a pair of layers reacts to touching, with the layers in the order of the pair
+struct Response {
+    event: Option<CollisionKind>,
+    /// Share of the overlap each body is pushed out by
+    push: (f32, f32),
+}
+
+fn response(a: CollisionLayer, b: CollisionLayer) -> Option<Response> {
+    use CollisionLayer::*;
+
+    let (event, push) = match (a, b) {
+        (Projectile, Orc) => (Some(CollisionKind::ProjectileOrc), (0.0, 0.0)),
+        // Players are predicted by their clients, so they don't get pushed around
+        (Orc, Player) => (Some(CollisionKind::OrcPlayer), (1.0, 0.0)),
+        (Orc, Orc) => (None, (0.5, 0.5)),
+        (Player | Orc, Wall) => (Some(CollisionKind::Wall), (1.0, 0.0)),
+        (Projectile, Wall) => (Some(CollisionKind::Wall), (0.0, 0.0)),
+        _ => return None,
+    };
+
+    Some(Response { event, push })
+}
+
+/// Dynamic bodies bucketed by every cell their bounds touch. Walls are few and large, so
+/// they are checked against every body instead.
+#[derive(Default)]
+pub struct SpatialHash {
+    cells: HashMap<IVec2, Vec<usize>>,
+}
+
+impl SpatialHash {
+    fn cell(position: Vec2) -> IVec2 {
+        (position / CELL_SIZE).floor().as_ivec2()
+    }
+
+    /// Fill the hash with `bodies`, reusing the cells of the last tick
+    fn rebuild(&mut self, bodies: &[Body]) {
+        self.cells.retain(|_, indices| {
+            let used = !indices.is_empty();
+            indices.clear();
+            used
+        });
+
+        for (index, body) in bodies.iter().enumerate() {
+            let (min, max) = body.bounds();
+            let (min, max) = (Self::cell(min), Self::cell(max));
+
+            for x in min.x..=max.x {
+                for y in min.y..=max.y {
+                    self.cells.entry(IVec2::new(x, y)).or_default().push(index);
+                }
+            }
+        }
+    }
+
+    /// Pairs of bodies whose bounds overlap, each reported once
+    fn pairs<'a>(&'a self, bodies: &'a [Body]) -> impl Iterator<Item = (usize, usize)> + 'a {
+        self.cells.iter().flat_map(move |(cell, indices)| {
+            indices.iter().enumerate().flat_map(move |(i, a)| {
+                indices[i + 1..].iter().filter_map(move |b| {
+                    let (a_min, a_max) = bodies[*a].bounds();
+                    let (b_min, b_max) = bodies[*b].bounds();
+
+                    if a_min.cmpgt(b_max).any() || b_min.cmpgt(a_max).any() {
+                        return None;
+                    }
+                    // Bodies sharing several cells are only reported by the one holding
+                    // the corner of their overlap
+                    (Self::cell(a_min.max(b_min)) == *cell).then_some((*a, *b))
+                })
+            })
+        })
+    }
+}
+
+/// Find every collision among `bodies` and `walls`. Returns the collisions and how far
+/// each body has to move to no longer overlap anything.
+fn step(hash: &mut SpatialHash, bodies: &[Body], walls: &[Body]) -> (Vec<Collision>, Vec<Vec2>) {
+    let mut collisions = Vec::new();
+    let mut corrections = vec![Vec2::ZERO; bodies.len()];
+
+    hash.rebuild(bodies);
+
+    let mut resolve = |a: usize, b: Option<usize>, a_body: &Body, b_body: &Body| {
+        // Pairs come in any order, responses are only defined for one of them
+        let (swapped, response) = match response(a_body.layer, b_body.layer) {
+            Some(response) => (false, response),
+            None => match response(b_body.layer, a_body.layer) {
+                Some(response) => (true, response),
+                None => return,
+            },
+        };
+        let contact = match contact(a_body, b_body) {
+            Some(contact) => contact,
+            None => return,
+        };
+
+        let (first, second) = if swapped {
+            (b_body, a_body)
+        } else {
+            (a_body, b_body)
+        };
+        if let Some(kind) = response.event {
+            collisions.push(Collision {
+                kind,
+                a: first.entity,
+                b: second.entity,
+            });
+        }
+
+        let (a_push, b_push) = if swapped {
+            (response.push.1, response.push.0)
+        } else {
+            response.push
+        };
+        corrections[a] -= contact.normal * contact.depth * a_push;
+        if let Some(b) = b {
+            corrections[b] += contact.normal * contact.depth * b_push;
+        }
+    };
+
+    for (a, b) in hash.pairs(bodies) {
+        resolve(a, Some(b), &bodies[a], &bodies[b]);
+    }
+
+    for (index, body) in bodies.iter().enumerate() {
+        for wall in walls {
+            resolve(index, None, body, wall);
+        }
+    }
+
+    (collisions, corrections)
+}
+
+fn contact(a: &Body, b: &Body) -> Option<Contact> {
+    match (a.collider, b.collider) {
+        (Collider::Circle { radius: a_radius }, Collider::Circle { radius: b_radius }) => {
+            // Projectiles are fast enough to pass through a body within a single tick
+            let sweeps =
+                a.layer == CollisionLayer::Projectile || b.layer == CollisionLayer::Projectile;
+            if sweeps {
+                let (swept, other) = if a.layer == CollisionLayer::Projectile {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                let distance = segment_distance(swept.start, swept.position, other.position);
+
+                return (distance < a_radius + b_radius).then_some(Contact {
+                    normal: Vec2::ZERO,
+                    depth: 0.0,
+                });
+            }
+
+            let offset = b.position - a.position;
+            let distance = offset.length();
+            let depth = a_radius + b_radius - distance;
+
+            (depth > 0.0).then(|| Contact {
+                // Bodies right on top of each other still need a way out
+                normal: offset.try_normalize().unwrap_or(Vec2::X),
+                depth,
+            })
+        }
+        (Collider::Circle { radius }, Collider::Aabb { half_extents }) => {
+            circle_aabb_contact(a.position, radius, b.position, half_extents)
+        }
+        (Collider::Aabb { half_extents }, Collider::Circle { radius }) => {
+            circle_aabb_contact(b.position, radius, a.position, half_extents).map(|contact| {
+                Contact {
+                    normal: -contact.normal,
+                    depth: contact.depth,
+                }
+            })
+        }
+        (
+            Collider::Aabb {
+                half_extents: a_half_extents,
+            },
+            Collider::Aabb {
+                half_extents: b_half_extents,
+            },
+        ) => {
+            let offset = b.position - a.position;
+            let overlap = a_half_extents + b_half_extents - offset.abs();
+            if overlap.min_element() <= 0.0 {
+                return None;
+            }
+
+            // Out along the axis which overlaps the least
+            Some(if overlap.x < overlap.y {
+                Contact {
+                    normal: Vec2::new(offset.x.signum(), 0.0),
+                    depth: overlap.x,
+                }
+            } else {
+                Contact {
+                    normal: Vec2::new(0.0, offset.y.signum()),
+                    depth: overlap.y,
+                }
+            })
+        }
+    }
+}
+
+fn circle_aabb_contact(
+    center: Vec2,
+    radius: f32,
+    box_center: Vec2,
+    half_extents: Vec2,
+) -> Option<Contact> {
+    let offset = center - box_center;
+    let closest = offset.clamp(-half_extents, half_extents);
+
+    if closest != offset {
+        let outside = offset - closest;
+        let distance = outside.length();
+
+        return (distance < radius).then(|| Contact {
+            normal: -outside / distance,
+            depth: radius - distance,
+        });
+    }
+
+    // The center is inside the box, leave through the nearest side
+    let to_side = half_extents - offset.abs();
+    Some(if to_side.x < to_side.y {
+        Contact {
+            normal: Vec2::new(-offset.x.signum(), 0.0),
+            depth: to_side.x + radius,
+        }
+    } else {
+        Contact {
+            normal: Vec2::new(0.0, -offset.y.signum()),
+            depth: to_side.y + radius,
+        }
+    })
+}
+
+pub struct CollisionPlugin;
+
+impl Plugin for CollisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<Collision>()
+            .add_startup_system(spawn_walls_system);
+    }
+}
+
+/// Wall in the world, never moves
+#[derive(Component)]
+pub struct Wall;
+
+/// Fence in the world, so nothing leaves it
+fn spawn_walls_system(mut commands: Commands) {
+    let center = WALL_DISTANCE + WALL_THICKNESS / 2.0;
+    let long_side = WALL_DISTANCE + WALL_THICKNESS;
+
+    for (position, half_extents) in [
+        (
+            Vec2::new(-center, 0.0),
+            Vec2::new(WALL_THICKNESS / 2.0, long_side),
+        ),
+        (
+            Vec2::new(center, 0.0),
+            Vec2::new(WALL_THICKNESS / 2.0, long_side),
+        ),
+        (
+            Vec2::new(0.0, -center),
+            Vec2::new(long_side, WALL_THICKNESS / 2.0),
+        ),
+        (
+            Vec2::new(0.0, center),
+            Vec2::new(long_side, WALL_THICKNESS / 2.0),
+        ),
+    ] {
+        commands.spawn((
+            TransformBundle::from_transform(Transform::from_translation(position.extend(0.0))),
+            Collider::Aabb { half_extents },
+            CollisionLayer::Wall,
+            Wall,
+        ));
+    }
+}
+
+/// Everything that collides, except for the walls which don't move
+type BodyQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static mut Transform,
+        &'static Collider,
+        &'static CollisionLayer,
+        Option<&'static Velocity>,
+    ),
+    Without<Wall>,
+>;
+
+/// Detect collisions after every physics step and push overlapping bodies apart
+pub fn collision_system(
+    mut hash: Local<SpatialHash>,
+    settings: Res<ServerSettings>,
+    mut collision_events: EventWriter<Collision>,
+    mut body_query: BodyQuery,
+    wall_query: Query<(Entity, &Transform, &Collider), With<Wall>>,
+) {
+    // Bodies moved by a whole physics step, however long the frame took
+    let timestep = settings.physics_timestep() as f32;
+    let bodies: Vec<Body> = body_query
+        .iter()
+        .map(|(entity, transform, collider, layer, velocity)| {
+            let position = transform.translation.truncate();
+            let moved = velocity.map_or(Vec2::ZERO, |velocity| velocity.0 * timestep);
+
+            Body {
+                entity,
+                layer: *layer,
+                collider: *collider,
+                position,
+                start: position - moved,
+            }
+        })
+        .collect();
+    let walls: Vec<Body> = wall_query
+        .iter()
+        .map(|(entity, transform, collider)| Body {
+            entity,
+            layer: CollisionLayer::Wall,
+            collider: *collider,
+            position: transform.translation.truncate(),
+            start: transform.translation.truncate(),
+        })
+        .collect();
+
+    let (collisions, corrections) = step(&mut hash, &bodies, &walls);
+
+    for (body, correction) in bodies.iter().zip(corrections) {
+        if correction == Vec2::ZERO {
+            continue;
+        }
+        if let Ok((_, mut transform, ..)) = body_query.get_mut(body.entity) {
+            transform.translation += correction.extend(0.0);
+        }
+    }
+
+    collision_events.send_batch(collisions);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn circle(index: u32, layer: CollisionLayer, position: Vec2) -> Body {
+        Body {
+            entity: Entity::from_raw(index),
+            layer,
+            collider: Collider::Circle { radius: 24.0 },
+            position,
+            start: position,
+        }
+    }
+
+    #[test]
+    fn overlapping_orcs_are_pushed_apart_once() {
+        let bodies = [
+            circle(0, CollisionLayer::Orc, Vec2::new(0.0, 0.0)),
+            // Straddling cell borders, so the pair shares several cells
+            circle(1, CollisionLayer::Orc, Vec2::new(40.0, 0.0)),
+        ];
+
+        let (collisions, corrections) = step(&mut SpatialHash::default(), &bodies, &[]);
+
+        assert!(collisions.is_empty());
+        assert_eq!(corrections, vec![Vec2::new(-4.0, 0.0), Vec2::new(4.0, 0.0)]);
+    }
+
+    #[test]
+    fn orcs_make_way_for_players() {
+        let bodies = [
+            circle(0, CollisionLayer::Player, Vec2::new(0.0, 0.0)),
+            circle(1, CollisionLayer::Orc, Vec2::new(0.0, 40.0)),
+        ];
+
+        let (collisions, corrections) = step(&mut SpatialHash::default(), &bodies, &[]);
+
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].kind, CollisionKind::OrcPlayer);
+        assert_eq!(collisions[0].a, Entity::from_raw(1));
+        assert_eq!(corrections, vec![Vec2::ZERO, Vec2::new(0.0, 8.0)]);
+    }
+
+    #[test]
+    fn projectiles_hit_orcs_between_ticks() {
+        let mut projectile = circle(0, CollisionLayer::Projectile, Vec2::new(100.0, 0.0));
+        projectile.collider = Collider::Circle { radius: 4.0 };
+        projectile.start = Vec2::new(-100.0, 0.0);
+        let bodies = [projectile, circle(1, CollisionLayer::Orc, Vec2::ZERO)];
+
+        let (collisions, corrections) = step(&mut SpatialHash::default(), &bodies, &[]);
+
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].kind, CollisionKind::ProjectileOrc);
+        assert_eq!(corrections, vec![Vec2::ZERO, Vec2::ZERO]);
+    }
+
+    #[test]
+    fn walls_push_bodies_out() {
+        let wall = Body {
+            entity: Entity::from_raw(9),
+            layer: CollisionLayer::Wall,
+            collider: Collider::Aabb {
+                half_extents: Vec2::new(50.0, 500.0),
+            },
+            position: Vec2::new(100.0, 0.0),
+            start: Vec2::new(100.0, 0.0),
+        };
+        let bodies = [
+            // Touching the wall
+            circle(0, CollisionLayer::Orc, Vec2::new(40.0, 0.0)),
+            // Already inside it
+            circle(1, CollisionLayer::Orc, Vec2::new(60.0, 100.0)),
+        ];
+
+        let (collisions, corrections) = step(&mut SpatialHash::default(), &bodies, &[wall]);
+
+        assert_eq!(collisions.len(), 2);
+        assert!(collisions
+            .iter()
+            .all(|collision| collision.kind == CollisionKind::Wall && collision.b == wall.entity));
+        assert_eq!(
+            corrections,
+            vec![Vec2::new(-14.0, 0.0), Vec2::new(-34.0, 0.0)]
+        );
+    }
+
+    // Run with `cargo test --release -p server -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn benchmark_thousands_of_entities() {
+        const TICKS: u32 = 60;
+
+        for count in [1_000, 5_000, 10_000] {
+            // About as crowded as a big wave around a group of players
+            let side = (count as f32).sqrt() * 60.0;
+            let bodies: Vec<Body> = (0..count)
+                .map(|index| {
+                    let position = (Vec2::new(rand::random(), rand::random()) - 0.5) * side;
+                    let layer = match index % 10 {
+                        0 => CollisionLayer::Player,
+                        1 => CollisionLayer::Projectile,
+                        _ => CollisionLayer::Orc,
+                    };
+                    circle(index, layer, position)
+                })
+                .collect();
+
+            let mut hash = SpatialHash::default();
+            let start = Instant::now();
+            let mut collisions = 0;
+            for _ in 0..TICKS {
+                collisions += step(&mut hash, &bodies, &[]).0.len();
+            }
+
+            println!(
+                "{} entities: {:?} per tick, {} collisions per tick",
+                count,
+                start.elapsed() / TICKS,
+                collisions / TICKS as usize
+            );
+        }
+    }
+}
@@ -2,3 +2,20 @@ use bevy::prelude::*;
 
 #[derive(Component, Debug)]
 pub struct Velocity(pub Vec2);
+
+#[derive(Component, Debug)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Self { current: max, max }
+    }
+
+    /// Share of the maximum health left
+    pub fn fraction(&self) -> f32 {
+        self.current / self.max
+    }
+}
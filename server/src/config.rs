@@ -0,0 +1,246 @@
+use std::{
+    error::Error,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use bevy::prelude::{Resource, Vec2};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+
+use shared::{
+    auth::{parse_private_key, PrivateKey},
+    DEFAULT_SERVER_PORT, WORLD_HALF_EXTENT,
+};
+
+/// Command line arguments. Anything passed here overrides the config file.
+#[derive(Parser, Debug)]
+#[command(about = "Dedicated server for Cool Survival Game")]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to a TOML config file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Address of the interface to bind to
+    #[arg(long)]
+    pub bind_address: Option<IpAddr>,
+
+    /// Address clients use to reach this server, if different from the bind address
+    #[arg(long)]
+    pub public_address: Option<IpAddr>,
+
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    #[arg(long)]
+    pub max_clients: Option<usize>,
+
+    /// Physics ticks per second
+    #[arg(long)]
+    pub tick_rate: Option<f64>,
+
+    #[arg(long)]
+    pub server_name: Option<String>,
+
+    #[arg(long)]
+    pub motd: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Print a new random private key for secure mode
+    GenerateKey,
+    /// Issue a connect token signed with the configured private key
+    IssueToken {
+        #[arg(short, long)]
+        username: String,
+
+        /// Client id sealed in the token, random if omitted
+        #[arg(long)]
+        client_id: Option<u64>,
+
+        /// File to write the token to
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+}
+
+#[derive(Resource, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind_address: IpAddr,
+    pub public_address: Option<IpAddr>,
+    pub port: u16,
+    pub max_clients: usize,
+    pub tick_rate: f64,
+    pub server_name: String,
+    pub motd: String,
+    /// Hex encoded key used to verify connect tokens. Enables secure mode when set.
+    pub private_key: Option<String>,
+    /// Invalid movement a player may send in a short time before getting kicked
+    pub movement_violation_limit: u32,
+    /// Distance in world units within which entities are replicated to a player
+    pub interest_radius: f32,
+    /// Bytes per second state snapshots may use on each connection
+    pub snapshot_bandwidth: u32,
+    /// Seconds between printing bandwidth statistics of every client, never if unset
+    pub bandwidth_report_interval: Option<f64>,
+    /// Seconds the server may rewind targets to judge a shot the way the shooter saw it
+    pub max_rewind: f64,
+    /// Usernames which are refused when joining, ignoring case
+    pub banned_usernames: Vec<String>,
+    /// Seconds the player of a dropped connection is kept for its client to come back,
+    /// resuming is disabled if zero
+    pub resume_grace_period: f64,
+    /// Seconds between the end of a wave and the start of the next, and before the first
+    pub wave_intermission: f64,
+    /// Where players join and respawn, as x and y
+    pub spawn_position: [f32; 2],
+    /// Seconds a player stays down before respawning
+    pub respawn_delay: f64,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            public_address: None,
+            port: DEFAULT_SERVER_PORT,
+            max_clients: 10,
+            tick_rate: 60.0,
+            server_name: "Cool Survival Server".to_owned(),
+            motd: String::new(),
+            private_key: None,
+            movement_violation_limit: 20,
+            interest_radius: 1500.0,
+            snapshot_bandwidth: 16_000,
+            bandwidth_report_interval: None,
+            max_rewind: 0.25,
+            banned_usernames: Vec::new(),
+            resume_grace_period: 30.0,
+            wave_intermission: 20.0,
+            spawn_position: [0.0, 0.0],
+            respawn_delay: 5.0,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Build the settings from defaults, then the config file, then the command line.
+    pub fn load(args: &Args) -> Result<Self, Box<dyn Error>> {
+        let mut settings = match &args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("Could not read config {}: {}", path.display(), e))?;
+                toml::from_str(&contents)
+                    .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?
+            }
+            None => Self::default(),
+        };
+
+        if let Some(bind_address) = args.bind_address {
+            settings.bind_address = bind_address;
+        }
+        if let Some(public_address) = args.public_address {
+            settings.public_address = Some(public_address);
+        }
+        if let Some(port) = args.port {
+            settings.port = port;
+        }
+        if let Some(max_clients) = args.max_clients {
+            settings.max_clients = max_clients;
+        }
+        if let Some(tick_rate) = args.tick_rate {
+            settings.tick_rate = tick_rate;
+        }
+        if let Some(server_name) = &args.server_name {
+            settings.server_name = server_name.clone();
+        }
+        if let Some(motd) = &args.motd {
+            settings.motd = motd.clone();
+        }
+
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.max_clients == 0 {
+            return Err("max_clients must be at least 1".into());
+        }
+        if !(self.tick_rate.is_finite() && self.tick_rate > 0.0) {
+            return Err("tick_rate must be a positive number".into());
+        }
+        if !(self.interest_radius.is_finite() && self.interest_radius > 0.0) {
+            return Err("interest_radius must be a positive number".into());
+        }
+        if self.snapshot_bandwidth == 0 {
+            return Err("snapshot_bandwidth must be at least 1".into());
+        }
+        if !(self.max_rewind.is_finite() && self.max_rewind >= 0.0) {
+            return Err("max_rewind must be zero or a positive number".into());
+        }
+        if !(self.resume_grace_period.is_finite() && self.resume_grace_period >= 0.0) {
+            return Err("resume_grace_period must be zero or a positive number".into());
+        }
+        if !(self.wave_intermission.is_finite() && self.wave_intermission >= 0.0) {
+            return Err("wave_intermission must be zero or a positive number".into());
+        }
+        if !self
+            .spawn_position
+            .iter()
+            .all(|coordinate| coordinate.abs() <= WORLD_HALF_EXTENT)
+        {
+            return Err(format!(
+                "spawn_position must be within {} of the origin",
+                WORLD_HALF_EXTENT
+            )
+            .into());
+        }
+        if !(self.respawn_delay.is_finite() && self.respawn_delay >= 0.0) {
+            return Err("respawn_delay must be zero or a positive number".into());
+        }
+        // Connect tokens are issued for the public address, so clients must be able to reach it
+        if self.private_key.is_some() && self.public_addr().ip().is_unspecified() {
+            return Err(
+                "public_address must be set in secure mode when bind_address is unspecified".into(),
+            );
+        }
+        self.private_key()?;
+
+        Ok(())
+    }
+
+    pub fn bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    pub fn public_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.public_address.unwrap_or(self.bind_address), self.port)
+    }
+
+    pub fn spawn_position(&self) -> Vec2 {
+        Vec2::from(self.spawn_position)
+    }
+
+    pub fn private_key(&self) -> Result<Option<PrivateKey>, String> {
+        self.private_key
+            .as_deref()
+            .map(parse_private_key)
+            .transpose()
+    }
+
+    pub fn is_banned(&self, username: &str) -> bool {
+        self.banned_usernames
+            .iter()
+            .any(|banned| banned.eq_ignore_ascii_case(username))
+    }
+
+    pub fn physics_timestep(&self) -> f64 {
+        1.0 / self.tick_rate
+    }
+}
@@ -0,0 +1,213 @@
+use bevy::prelude::*;
+
+use crate::{
+    components::Health,
+    config::ServerSettings,
+    orc::{events::OrcAttack, Orc},
+    player::events::ResumePlayer,
+    projectile::events::ProjectileHit,
+    Players, SM,
+};
+use shared::*;
+
+pub mod events {
+    use bevy::prelude::Entity;
+
+    pub struct Damage {
+        pub target: Entity,
+        pub amount: f32,
+    }
+}
+
+const PROJECTILE_DAMAGE: f32 = 25.0;
+
+/// A player at zero health, waiting to respawn
+#[derive(Component)]
+pub struct Downed {
+    /// Elapsed server time at which the player respawns
+    respawn_at: f64,
+}
+
+pub struct HealthPlugin;
+
+impl Plugin for HealthPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<events::Damage>()
+            .add_system(projectile_damage_system)
+            .add_system(orc_attack_damage_system)
+            .add_system(
+                damage_system
+                    .after(projectile_damage_system)
+                    .after(orc_attack_damage_system),
+            )
+            .add_system(respawn_system)
+            .add_system(player_health_system.after(damage_system))
+            .add_system(resume_health_system);
+    }
+}
+
+/// Projectiles hurt the orcs they hit. Players shooting each other doesn't hurt.
+fn projectile_damage_system(
+    mut events: EventReader<ProjectileHit>,
+    mut damage_events: EventWriter<events::Damage>,
+    orc_query: Query<(), With<Orc>>,
+) {
+    for event in events.iter() {
+        if orc_query.contains(event.target) {
+            damage_events.send(events::Damage {
+                target: event.target,
+                amount: PROJECTILE_DAMAGE,
+            });
+        }
+    }
+}
+
+fn orc_attack_damage_system(
+    mut events: EventReader<OrcAttack>,
+    mut damage_events: EventWriter<events::Damage>,
+) {
+    for event in events.iter() {
+        damage_events.send(events::Damage {
+            target: event.target,
+            amount: event.damage,
+        });
+    }
+}
+
+/// Apply damage. Orcs die right away, players go down until they respawn.
+fn damage_system(
+    mut commands: Commands,
+    mut events: EventReader<events::Damage>,
+    mut server_msg_events: EventWriter<SM>,
+    time: Res<Time>,
+    settings: Res<ServerSettings>,
+    players: Res<Players>,
+    mut query: Query<(&mut Health, Option<&Orc>), Without<Downed>>,
+) {
+    for event in events.iter() {
+        let (mut health, orc) = match query.get_mut(event.target) {
+            Ok(target) => target,
+            // Down, or already gone
+            Err(_) => continue,
+        };
+
+        // Several hits in the same frame only kill once
+        if health.current <= 0.0 {
+            continue;
+        }
+
+        health.current = (health.current - event.amount).max(0.0);
+        if health.current > 0.0 {
+            continue;
+        }
+
+        if orc.is_some() {
+            commands.entity(event.target).despawn();
+            continue;
+        }
+
+        commands.entity(event.target).insert(Downed {
+            respawn_at: time.elapsed_seconds_f64() + settings.respawn_delay,
+        });
+
+        if let Some((client_id, player_info)) = players
+            .0
+            .iter()
+            .find(|(_, player_info)| player_info.entity == event.target)
+        {
+            println!("{} went down", player_info.username);
+            server_msg_events.send((
+                *client_id,
+                ServerMessage::PlayerDowned {
+                    respawn_in: settings.respawn_delay as f32,
+                },
+            ));
+        }
+    }
+}
+
+fn respawn_system(
+    mut commands: Commands,
+    mut server_msg_events: EventWriter<SM>,
+    time: Res<Time>,
+    settings: Res<ServerSettings>,
+    players: Res<Players>,
+    mut query: Query<(Entity, &Downed, &mut Health, &mut Transform)>,
+) {
+    let now = time.elapsed_seconds_f64();
+
+    for (entity, downed, mut health, mut player_tf) in query.iter_mut() {
+        if now < downed.respawn_at {
+            continue;
+        }
+
+        let position = settings.spawn_position();
+        commands.entity(entity).remove::<Downed>();
+        health.current = health.max;
+        player_tf.translation = position.extend(0.0);
+
+        if let Some((client_id, _)) = players
+            .0
+            .iter()
+            .find(|(_, player_info)| player_info.entity == entity)
+        {
+            server_msg_events.send((*client_id, ServerMessage::PlayerRespawned { position }));
+        }
+    }
+}
+
+/// Tell players about changes to their own health. Everyone else gets it with snapshots.
+fn player_health_system(
+    mut server_msg_events: EventWriter<SM>,
+    players: Res<Players>,
+    query: Query<(&Health, ChangeTrackers<Health>)>,
+) {
+    for (client_id, player_info) in players.0.iter() {
+        if let Ok((health, tracker)) = query.get(player_info.entity) {
+            if tracker.is_changed() {
+                server_msg_events.send((*client_id, player_health(health)));
+            }
+        }
+    }
+}
+
+/// A client taking over a player doesn't know its health yet
+fn resume_health_system(
+    mut events: EventReader<ResumePlayer>,
+    mut server_msg_events: EventWriter<SM>,
+    time: Res<Time>,
+    players: Res<Players>,
+    query: Query<(&Health, Option<&Downed>)>,
+) {
+    for event in events.iter() {
+        let client_id = match players
+            .0
+            .iter()
+            .find(|(_, player_info)| player_info.entity == event.entity)
+        {
+            Some((client_id, _)) => *client_id,
+            None => continue,
+        };
+
+        if let Ok((health, downed)) = query.get(event.entity) {
+            server_msg_events.send((client_id, player_health(health)));
+
+            if let Some(downed) = downed {
+                server_msg_events.send((
+                    client_id,
+                    ServerMessage::PlayerDowned {
+                        respawn_in: (downed.respawn_at - time.elapsed_seconds_f64()).max(0.0)
+                            as f32,
+                    },
+                ));
+            }
+        }
+    }
+}
+
+fn player_health(health: &Health) -> ServerMessage {
+    ServerMessage::PlayerHealth {
+        health: health.current,
+        max_health: health.max,
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+
+use crate::{
+    components::Health, config::ServerSettings, orc::Orc, snapshot::rotation_angle, Players,
+    ServerTick, SM,
+};
+use shared::{snapshot::NetId, *};
+
+/// Side length of a grid cell in world units
+const CELL_SIZE: f32 = 256.0;
+/// How much further than the interest radius an entity may go before a client forgets it,
+/// so entities near the edge don't flicker in and out
+const INTEREST_HYSTERESIS: f32 = CELL_SIZE;
+
+/// Replicated entities bucketed by the grid cell they are in
+#[derive(Default)]
+struct SpatialGrid {
+    cells: HashMap<IVec2, Vec<(NetId, Vec2)>>,
+}
+
+impl SpatialGrid {
+    fn cell(position: Vec2) -> IVec2 {
+        (position / CELL_SIZE).floor().as_ivec2()
+    }
+
+    fn insert(&mut self, id: NetId, position: Vec2) {
+        self.cells
+            .entry(Self::cell(position))
+            .or_default()
+            .push((id, position));
+    }
+
+    /// Entities at most `radius` away from `center`
+    fn query(&self, center: Vec2, radius: f32) -> impl Iterator<Item = NetId> + '_ {
+        let min = Self::cell(center - Vec2::splat(radius));
+        let max = Self::cell(center + Vec2::splat(radius));
+
+        (min.x..=max.x)
+            .flat_map(move |x| (min.y..=max.y).map(move |y| IVec2::new(x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .filter(move |(_, position)| position.distance_squared(center) <= radius * radius)
+            .map(|(id, _)| *id)
+    }
+}
+
+/// Entities spawned on each client's side, keyed by client id. A client is only listed
+/// once its join snapshot was sent.
+#[derive(Resource, Default)]
+pub struct ClientInterests(pub HashMap<u64, HashSet<NetId>>);
+
+pub struct InterestPlugin;
+
+impl Plugin for InterestPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ClientInterests::default())
+            .add_system(interest_system);
+    }
+}
+
+/// Spawn entities which came near a player on their client and despawn the ones which
+/// went too far away. The first pass for a new client sends its join snapshot.
+#[allow(clippy::too_many_arguments)]
+fn interest_system(
+    mut last_update_tick: Local<u64>,
+    mut server_msg_events: EventWriter<SM>,
+    mut interests: ResMut<ClientInterests>,
+    tick: Res<ServerTick>,
+    players: Res<Players>,
+    settings: Res<ServerSettings>,
+    transform_query: Query<&Transform>,
+    health_query: Query<&Health>,
+    orc_query: Query<(Entity, &Transform, &Health, &Orc)>,
+) {
+    if tick.0 == *last_update_tick {
+        return;
+    }
+    *last_update_tick = tick.0;
+
+    let mut grid = SpatialGrid::default();
+    let mut player_infos = HashMap::new();
+    let mut orc_entities = HashMap::new();
+
+    for player_info in players.0.values() {
+        if let Ok(player_tf) = transform_query.get(player_info.entity) {
+            grid.insert(
+                NetId::Player(player_info.id),
+                player_tf.translation.truncate(),
+            );
+            player_infos.insert(player_info.id, player_info);
+        }
+    }
+    for (entity, orc_tf, _, orc) in orc_query.iter() {
+        grid.insert(NetId::Orc(orc.0), orc_tf.translation.truncate());
+        orc_entities.insert(orc.0, entity);
+    }
+
+    let entity_snapshot = |id: &NetId| -> Option<EntitySnapshot> {
+        match *id {
+            NetId::Player(id) => {
+                let player_info = player_infos.get(&id)?;
+                let player_tf = transform_query.get(player_info.entity).ok()?;
+                let health = health_query.get(player_info.entity).ok()?;
+
+                Some(EntitySnapshot::Player {
+                    id,
+                    username: player_info.username.clone(),
+                    position: player_tf.translation.truncate(),
+                    rotation: rotation_angle(player_tf.rotation),
+                    health: health.fraction(),
+                })
+            }
+            NetId::Orc(id) => {
+                let (_, orc_tf, health, _) = orc_query.get(*orc_entities.get(&id)?).ok()?;
+
+                Some(EntitySnapshot::Orc {
+                    id,
+                    position: orc_tf.translation.truncate(),
+                    rotation: rotation_angle(orc_tf.rotation),
+                    health: health.fraction(),
+                })
+            }
+        }
+    };
+
+    // Only entities which made it into a message become known to the client, the others
+    // are tried again later
+    let with_snapshot = |id: &NetId| Some((*id, entity_snapshot(id)?));
+
+    interests
+        .0
+        .retain(|client_id, _| players.0.contains_key(client_id));
+
+    for (client_id, player_info) in players.0.iter() {
+        // Components of a player who just joined are not inserted yet
+        let center = match transform_query.get(player_info.entity) {
+            Ok(player_tf) => player_tf.translation.truncate(),
+            Err(_) => continue,
+        };
+
+        // Clients predict their own player, it is never replicated to them
+        let own_id = NetId::Player(player_info.id);
+        let nearby: HashSet<NetId> = grid
+            .query(center, settings.interest_radius)
+            .filter(|id| *id != own_id)
+            .collect();
+
+        let known = match interests.0.get_mut(client_id) {
+            Some(known) => known,
+            None => {
+                let (sent, entities): (HashSet<NetId>, Vec<EntitySnapshot>) =
+                    nearby.iter().filter_map(with_snapshot).unzip();
+
+                server_msg_events.send((*client_id, ServerMessage::WorldSnapshot { entities }));
+                interests.0.insert(*client_id, sent);
+                continue;
+            }
+        };
+
+        let in_range: HashSet<NetId> = grid
+            .query(center, settings.interest_radius + INTEREST_HYSTERESIS)
+            .collect();
+
+        let left: Vec<NetId> = known
+            .iter()
+            .filter(|id| !in_range.contains(id))
+            .copied()
+            .collect();
+        let (sent, entered): (Vec<NetId>, Vec<EntitySnapshot>) = nearby
+            .iter()
+            .filter(|id| !known.contains(id))
+            .filter_map(with_snapshot)
+            .unzip();
+
+        for id in &left {
+            known.remove(id);
+        }
+        known.extend(sent);
+
+        if !left.is_empty() {
+            server_msg_events.send((*client_id, ServerMessage::DespawnEntities { ids: left }));
+        }
+        if !entered.is_empty() {
+            server_msg_events.send((
+                *client_id,
+                ServerMessage::SpawnEntities { entities: entered },
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_checks_distance_across_cells() {
+        let mut grid = SpatialGrid::default();
+        grid.insert(NetId::Orc(1), Vec2::new(-10.0, 0.0));
+        grid.insert(NetId::Orc(2), Vec2::new(300.0, 300.0));
+        grid.insert(NetId::Orc(3), Vec2::new(600.0, 0.0));
+
+        let mut found: Vec<_> = grid.query(Vec2::new(100.0, 0.0), 450.0).collect();
+        found.sort_by_key(|id| format!("{:?}", id));
+
+        assert_eq!(found, vec![NetId::Orc(1), NetId::Orc(2)]);
+    }
+}
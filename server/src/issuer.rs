@@ -0,0 +1,50 @@
+use std::{error::Error, fs::File, path::Path, time::SystemTime};
+
+use rand::RngCore;
+
+use crate::config::ServerSettings;
+use shared::{
+    auth::{format_private_key, issue_connect_token, write_token_file, PrivateKey},
+    UserData,
+};
+
+pub fn generate_key() {
+    let mut key: PrivateKey = Default::default();
+    rand::thread_rng().fill_bytes(&mut key);
+
+    println!("{}", format_private_key(&key));
+}
+
+/// Write a connect token for the server described by `settings` to `output`.
+pub fn issue_token(
+    settings: &ServerSettings,
+    username: &str,
+    client_id: Option<u64>,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let private_key = settings
+        .private_key()?
+        .ok_or("No private_key is configured, the server is running in unsecure mode")?;
+
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let client_id = client_id.unwrap_or_else(rand::random);
+
+    let server_addr = settings.public_addr();
+    let token = issue_connect_token(
+        current_time,
+        client_id,
+        vec![server_addr],
+        &UserData::new(username.trim().to_owned()),
+        &private_key,
+    )?;
+
+    let mut file = File::create(output)?;
+    write_token_file(&mut file, server_addr, &token)?;
+
+    println!(
+        "Issued token for client {} to {}",
+        client_id,
+        output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,291 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::*;
+
+use crate::{config::ServerSettings, health::Downed, orc::Orc, Players, ServerTick, CM};
+use shared::{snapshot::NetId, *};
+
+pub mod events {
+    use bevy::prelude::Vec2;
+    use shared::snapshot::NetId;
+
+    /// Any shot, judged at the tick the shooter saw
+    pub struct ShotFired {
+        /// Client id of the shooter
+        pub shooter: u64,
+        pub origin: Vec2,
+        pub direction: f32,
+        /// The rewound tick the shot was judged at
+        pub tick: u64,
+        /// How far the shot gets before hitting something or running out
+        pub range: f32,
+        /// What the shot hit in the world as the shooter saw it, at the end of its range
+        pub target: Option<NetId>,
+    }
+}
+
+/// Radius of players and orcs when checking for hits
+const HIT_RADIUS: f32 = 32.0;
+/// Shots a player may fire per second in the long run
+const SHOTS_PER_SECOND: f32 = 8.0;
+/// Allows a few shots which were delayed by the network to arrive at once
+const MAX_SHOT_BUDGET: f32 = 3.0;
+
+/// Shots a player may still fire. Refills in real time, so sending more shots than the
+/// fire rate allows doesn't get any more of them through.
+#[derive(Component, Default)]
+pub struct ShotBudget {
+    available: f32,
+    last_refill: Option<f64>,
+}
+
+impl ShotBudget {
+    fn refill(&mut self, now: f64) {
+        if let Some(last_refill) = self.last_refill {
+            let earned = (now - last_refill) as f32 * SHOTS_PER_SECOND;
+            self.available = (self.available + earned).min(MAX_SHOT_BUDGET);
+        } else {
+            self.available = MAX_SHOT_BUDGET;
+        }
+
+        self.last_refill = Some(now);
+    }
+
+    /// Use up one shot, if there is one left
+    fn take(&mut self) -> bool {
+        if self.available < 1.0 {
+            return false;
+        }
+
+        self.available -= 1.0;
+        true
+    }
+}
+
+/// Positions of every player and orc during the last ticks, oldest first
+#[derive(Resource, Default)]
+struct PositionHistory(VecDeque<(u64, HashMap<NetId, Vec2>)>);
+
+impl PositionHistory {
+    /// Positions at `tick`, or at the newest tick before it which was recorded
+    fn at(&self, tick: u64) -> Option<&HashMap<NetId, Vec2>> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(recorded_tick, _)| *recorded_tick <= tick)
+            .or_else(|| self.0.front())
+            .map(|(_, positions)| positions)
+    }
+}
+
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<events::ShotFired>()
+            .insert_resource(PositionHistory::default())
+            .add_system(record_history_system)
+            .add_system(shot_system);
+    }
+}
+
+fn max_rewind_ticks(settings: &ServerSettings) -> u64 {
+    (settings.max_rewind * settings.tick_rate).round() as u64
+}
+
+fn record_history_system(
+    mut last_recorded_tick: Local<u64>,
+    mut history: ResMut<PositionHistory>,
+    tick: Res<ServerTick>,
+    settings: Res<ServerSettings>,
+    players: Res<Players>,
+    transform_query: Query<&Transform>,
+    orc_query: Query<(&Transform, &Orc)>,
+) {
+    if tick.0 == *last_recorded_tick {
+        return;
+    }
+    *last_recorded_tick = tick.0;
+
+    let player_positions = players.0.values().filter_map(|player_info| {
+        let player_tf = transform_query.get(player_info.entity).ok()?;
+        Some((
+            NetId::Player(player_info.id),
+            player_tf.translation.truncate(),
+        ))
+    });
+    let orc_positions = orc_query
+        .iter()
+        .map(|(orc_tf, orc)| (NetId::Orc(orc.0), orc_tf.translation.truncate()));
+
+    history
+        .0
+        .push_back((tick.0, player_positions.chain(orc_positions).collect()));
+
+    let oldest_tick = tick.0.saturating_sub(max_rewind_ticks(&settings));
+    while history
+        .0
+        .front()
+        .is_some_and(|(recorded_tick, _)| *recorded_tick < oldest_tick)
+    {
+        history.0.pop_front();
+    }
+}
+
+/// Cast shots against the targets where the shooter saw them, at most `max_rewind` ago
+#[allow(clippy::too_many_arguments)]
+fn shot_system(
+    time: Res<Time>,
+    mut client_msg_events: EventReader<CM>,
+    mut fired_events: EventWriter<events::ShotFired>,
+    history: Res<PositionHistory>,
+    tick: Res<ServerTick>,
+    settings: Res<ServerSettings>,
+    players: Res<Players>,
+    transform_query: Query<&Transform>,
+    downed_query: Query<(), With<Downed>>,
+    mut budget_query: Query<&mut ShotBudget>,
+) {
+    // Projectiles disappear after flying this far
+    let max_range = PROJECTILE_SPEED * PROJECTILE_LIFETIME;
+
+    for (client_id, client_msg) in client_msg_events.iter() {
+        if let ClientMessage::Shoot {
+            direction,
+            tick: seen_tick,
+        } = client_msg
+        {
+            let player_info = match players.0.get(client_id) {
+                Some(player_info) => player_info,
+                None => continue,
+            };
+            if downed_query.contains(player_info.entity) {
+                continue;
+            }
+            match budget_query.get_mut(player_info.entity) {
+                Ok(mut budget) => {
+                    budget.refill(time.elapsed_seconds_f64());
+                    if !budget.take() {
+                        continue;
+                    }
+                }
+                Err(_) => continue,
+            }
+            let origin = match transform_query.get(player_info.entity) {
+                Ok(player_tf) => player_tf.translation.truncate(),
+                Err(_) => continue,
+            };
+
+            // Clients can't see the future, and seeing too far into the past is cheating
+            let rewind_tick =
+                (*seen_tick).clamp(tick.0.saturating_sub(max_rewind_ticks(&settings)), tick.0);
+            let positions = match history.at(rewind_tick) {
+                Some(positions) => positions,
+                None => continue,
+            };
+
+            let ray = Vec2::from_angle(*direction);
+
+            // Shots fly through players, who can't hurt each other anyway. Nothing outside
+            // the area of interest was on the shooter's screen.
+            let hit = positions
+                .iter()
+                .filter(|(id, _)| matches!(id, NetId::Orc(_)))
+                .filter_map(|(id, center)| {
+                    let distance = ray_distance(origin, ray, *center, HIT_RADIUS)?;
+                    Some((distance, *id))
+                })
+                .filter(|(distance, _)| *distance <= settings.interest_radius.min(max_range))
+                .min_by(|(a, _), (b, _)| a.total_cmp(b));
+
+            fired_events.send(events::ShotFired {
+                shooter: *client_id,
+                origin,
+                direction: *direction,
+                tick: rewind_tick,
+                range: hit.map_or(max_range, |(distance, _)| distance),
+                target: hit.map(|(_, target)| target),
+            });
+        }
+    }
+}
+
+/// Distance along a ray to where it enters a circle, if it does. A ray starting inside
+/// the circle enters it right away.
+fn ray_distance(origin: Vec2, direction: Vec2, center: Vec2, radius: f32) -> Option<f32> {
+    let to_center = center - origin;
+    let along = to_center.dot(direction);
+    let miss_squared = to_center.length_squared() - along * along;
+
+    if miss_squared > radius * radius {
+        return None;
+    }
+
+    let half_chord = (radius * radius - miss_squared).sqrt();
+    if along + half_chord < 0.0 {
+        // Behind the origin
+        return None;
+    }
+
+    Some((along - half_chord).max(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shot_budget_limits_the_fire_rate() {
+        let mut budget = ShotBudget::default();
+        budget.refill(10.0);
+
+        for _ in 0..MAX_SHOT_BUDGET as usize {
+            assert!(budget.take());
+        }
+        assert!(!budget.take());
+
+        // Spamming within the same instant gets nothing through
+        budget.refill(10.0);
+        assert!(!budget.take());
+
+        budget.refill(10.0 + 1.0 / SHOTS_PER_SECOND as f64);
+        assert!(budget.take());
+        assert!(!budget.take());
+
+        budget.refill(1000.0);
+        assert_eq!(budget.available, MAX_SHOT_BUDGET);
+    }
+
+    #[test]
+    fn ray_hits_circles_in_front() {
+        let hit = ray_distance(Vec2::ZERO, Vec2::X, Vec2::new(100.0, 10.0), 20.0).unwrap();
+        assert!((hit - (100.0 - 300.0_f32.sqrt())).abs() < 1e-4);
+
+        assert_eq!(
+            ray_distance(Vec2::ZERO, Vec2::X, Vec2::new(100.0, 30.0), 20.0),
+            None
+        );
+        assert_eq!(
+            ray_distance(Vec2::ZERO, Vec2::X, Vec2::new(-100.0, 0.0), 20.0),
+            None
+        );
+        assert_eq!(
+            ray_distance(Vec2::ZERO, Vec2::X, Vec2::new(5.0, 0.0), 20.0),
+            Some(0.0)
+        );
+    }
+
+    #[test]
+    fn history_falls_back_to_older_ticks() {
+        let mut history = PositionHistory::default();
+        for tick in [10, 11, 13] {
+            let positions = HashMap::from([(NetId::Orc(1), Vec2::new(tick as f32, 0.0))]);
+            history.0.push_back((tick, positions));
+        }
+
+        let x_at = |tick| history.at(tick).unwrap()[&NetId::Orc(1)].x;
+        assert_eq!(x_at(12), 11.0);
+        assert_eq!(x_at(13), 13.0);
+        assert_eq!(x_at(5), 10.0);
+    }
+}
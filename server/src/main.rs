@@ -1,43 +1,169 @@
 use std::{
     collections::HashMap,
-    net::{SocketAddr, UdpSocket},
+    error::Error,
+    net::UdpSocket,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::SystemTime,
 };
 
-use bevy::{prelude::*, time::FixedTimestep};
+use bevy::{app::AppExit, prelude::*, time::FixedTimestep};
 use bevy_renet::{renet::ServerEvent, *};
+use clap::Parser;
 
+use bandwidth::{BandwidthPlugin, BandwidthStats};
+use collision::{collision_system, CollisionPlugin};
 use components::Velocity;
-use orc::OrcPlugin;
+use config::{Args, Command, ServerSettings};
+use health::HealthPlugin;
+use interest::InterestPlugin;
+use lag_compensation::LagCompensationPlugin;
+use orc::{orc_ai_system, orc_collision_system, orc_flee_system, OrcPlugin};
 use player::{events::*, PlayerPlugin};
-use shared::*;
+use projectile::ProjectilePlugin;
+use session::{has_session, has_slot, take_session, HeldSessions, SessionPlugin};
+use shared::{
+    channels::Channel,
+    username::{unique_username, validate_username},
+    *,
+};
+use snapshot::SnapshotPlugin;
+use wave::WavePlugin;
 
+mod bandwidth;
+mod collision;
 mod components;
+mod config;
+mod health;
+mod interest;
+mod issuer;
+mod lag_compensation;
 mod orc;
 mod player;
+mod projectile;
+mod session;
+mod snapshot;
+mod wave;
 
-const MAX_CLIENTS: usize = 10;
-const PLAYER_SPAWN: Vec2 = Vec2::ZERO;
-const PHYSICS_TIMESTEP: f64 = 1.0 / 60.0; // 60 FPS
+/// Time given to a disconnect message to reach the client before it is disconnected
+const DISCONNECT_DELAY: f32 = 0.5;
+/// Connections accepted beyond `max_clients`, only to tell those clients the server is full
+const FULL_SERVER_SLOTS: usize = 4;
 
 // u64 value corresponds to the recipient/sender id
 type SM = (u64, ServerMessage);
 type CM = (u64, ClientMessage);
 
-struct Broadcast {
-    message: ServerMessage,
-    except: Option<u64>,
+/// Drop a client, telling it why
+struct DisconnectClient {
+    id: u64,
+    kind: DisconnectKind,
+    reason: String,
 }
 
 struct PlayerInfo {
+    /// Id the player is known by on the wire, assigned by the server
+    id: u64,
     entity: Entity,
     username: String,
+    /// Lets the client take over this player after its connection dropped
+    resume_token: u64,
 }
 
+/// Players keyed by their netcode client id
 #[derive(Resource, Default)]
 struct Players(HashMap<u64, PlayerInfo>);
 
+#[derive(Resource)]
+struct NextPlayerId(u64);
+
+/// Number of physics ticks simulated so far. Stamped on state snapshots and pongs.
+#[derive(Resource, Default)]
+struct ServerTick(u64);
+
+/// Clients which will be disconnected once their timer finishes
+#[derive(Resource, Default)]
+struct PendingDisconnects(HashMap<u64, Timer>);
+
+/// Set by the Ctrl-C handler
+#[derive(Resource, Default, Clone)]
+struct ShutdownRequested(Arc<AtomicBool>);
+
+impl ShutdownRequested {
+    fn get(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
 fn main() {
+    let args = Args::parse();
+    let settings = match ServerSettings::load(&args) {
+        Ok(settings) => settings,
+        Err(error) => {
+            eprintln!("Failed to load server settings: {}", error);
+            process::exit(1);
+        }
+    };
+
+    match &args.command {
+        Some(Command::GenerateKey) => {
+            issuer::generate_key();
+            return;
+        }
+        Some(Command::IssueToken {
+            username,
+            client_id,
+            output,
+        }) => {
+            if let Err(error) = issuer::issue_token(&settings, username, *client_id, output) {
+                eprintln!("Failed to issue connect token: {}", error);
+                process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+
+    let server = match create_renet_server(&settings) {
+        Ok(server) => server,
+        Err(error) => {
+            eprintln!(
+                "Failed to start server on {}: {}",
+                settings.bind_addr(),
+                error
+            );
+            process::exit(1);
+        }
+    };
+
+    println!(
+        "{} listening on {} (public address {}, max {} clients)",
+        settings.server_name,
+        settings.bind_addr(),
+        settings.public_addr(),
+        settings.max_clients
+    );
+    if !settings.motd.is_empty() {
+        println!("MOTD: {}", settings.motd);
+    }
+
+    let shutdown_requested = ShutdownRequested::default();
+    let handler_flag = shutdown_requested.0.clone();
+    let handler_result = ctrlc::set_handler(move || {
+        // A second Ctrl-C doesn't wait for the clients
+        if handler_flag.swap(true, Ordering::SeqCst) {
+            process::exit(1);
+        }
+    });
+    if let Err(error) = handler_result {
+        eprintln!("Failed to set the Ctrl-C handler: {}", error);
+    }
+
+    let physics_timestep = settings.physics_timestep();
+
     App::new()
         .add_plugins(MinimalPlugins.set(CorePlugin {
             task_pool_options: TaskPoolOptions::with_num_threads(1),
@@ -45,89 +171,104 @@ fn main() {
         .add_plugin(RenetServerPlugin::default())
         .add_plugin(PlayerPlugin)
         .add_plugin(OrcPlugin)
-        .insert_resource(create_renet_server())
+        .add_plugin(InterestPlugin)
+        .add_plugin(BandwidthPlugin)
+        .add_plugin(LagCompensationPlugin)
+        .add_plugin(CollisionPlugin)
+        .add_plugin(HealthPlugin)
+        .add_plugin(ProjectilePlugin)
+        .add_plugin(SnapshotPlugin)
+        .add_plugin(SessionPlugin)
+        .add_plugin(WavePlugin)
+        .insert_resource(server)
+        .insert_resource(settings)
         .insert_resource(Players::default())
-        .add_event::<Broadcast>()
+        .insert_resource(PendingDisconnects::default())
+        .insert_resource(shutdown_requested)
+        .insert_resource(NextPlayerId(1))
+        .insert_resource(ServerTick::default())
+        .add_event::<DisconnectClient>()
         .add_event::<SM>()
         .add_event::<CM>()
         .add_system(handle_incoming_messages)
-        .add_system(handle_outgoing_broadcasts)
         .add_system(handle_outgoing_messages)
         .add_system(handle_server_events)
+        .add_system(disconnect_clients_system)
+        .add_system(pending_disconnects_system)
+        .add_system(shutdown_system)
+        .add_system(ping_system)
         .add_system_set(
             SystemSet::new()
-                .with_run_criteria(FixedTimestep::step(PHYSICS_TIMESTEP))
-                .with_system(velocity_system),
+                .with_run_criteria(FixedTimestep::step(physics_timestep))
+                .with_system(tick_system)
+                .with_system(
+                    orc_ai_system
+                        .after(tick_system)
+                        .after(orc_flee_system)
+                        .after(orc_collision_system),
+                )
+                .with_system(velocity_system.after(orc_ai_system))
+                .with_system(collision_system.after(velocity_system)),
         )
         .run();
 }
 
-fn create_renet_server() -> renet::RenetServer {
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap();
+fn create_renet_server(settings: &ServerSettings) -> Result<renet::RenetServer, Box<dyn Error>> {
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+
+    let authentication = match settings.private_key()? {
+        Some(private_key) => renet::ServerAuthentication::Secure { private_key },
+        None => renet::ServerAuthentication::Unsecure,
+    };
 
-    let server_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
     let server_config = renet::ServerConfig::new(
-        MAX_CLIENTS,
+        settings.max_clients + FULL_SERVER_SLOTS,
         PROTOCOL_ID,
-        server_addr,
-        renet::ServerAuthentication::Unsecure,
+        settings.public_addr(),
+        authentication,
     );
 
-    let connection_config = renet::RenetConnectionConfig::default();
-    let socket = UdpSocket::bind(server_addr).unwrap();
+    let connection_config = channels::connection_config();
+    let socket = UdpSocket::bind(settings.bind_addr())?;
 
-    renet::RenetServer::new(current_time, server_config, connection_config, socket).unwrap()
+    renet::RenetServer::new(current_time, server_config, connection_config, socket)
+        .map_err(|e| e.into())
 }
 
 fn handle_incoming_messages(mut server: ResMut<renet::RenetServer>, mut events: EventWriter<CM>) {
-    let channel_id = 0;
-
     for client_id in server.clients_id() {
-        while let Some(serialized_msg) = server.receive_message(client_id, channel_id) {
-            match bincode::deserialize::<ClientMessage>(&serialized_msg) {
-                Ok(client_msg) => events.send((client_id, client_msg)),
-                Err(error) => eprintln!(
-                    "An error occured while deserializing client message:\n{}",
-                    error
-                ),
+        for channel in Channel::ALL {
+            while let Some(serialized_msg) = server.receive_message(client_id, channel.id()) {
+                match wire::deserialize::<ClientMessage>(&serialized_msg) {
+                    Ok(client_msg) => events.send((client_id, client_msg)),
+                    Err(error) => eprintln!(
+                        "An error occured while deserializing client message:\n{}",
+                        error
+                    ),
+                }
             }
         }
     }
 }
 
-/// Handle server messages which have to be sent to all clients
-fn handle_outgoing_broadcasts(
+/// Handle server messages which have to be sent to only one specific client
+fn handle_outgoing_messages(
     mut server: ResMut<renet::RenetServer>,
-    mut events: EventReader<Broadcast>,
+    mut events: EventReader<SM>,
+    mut bandwidth_stats: ResMut<BandwidthStats>,
 ) {
-    let channel_id = 0;
+    for (recipient_id, server_msg) in events.iter() {
+        let channel_id = server_msg.channel().id();
 
-    for Broadcast { message, except } in events.iter() {
-        match bincode::serialize(message) {
+        match wire::serialize(server_msg) {
             Ok(serialized_msg) => {
-                if let Some(except) = except {
-                    server.broadcast_message_except(*except, channel_id, serialized_msg);
-                } else {
-                    server.broadcast_message(channel_id, serialized_msg);
-                }
+                bandwidth_stats
+                    .0
+                    .entry(*recipient_id)
+                    .or_default()
+                    .record(serialized_msg.len());
+                server.send_message(*recipient_id, channel_id, serialized_msg);
             }
-            Err(error) => eprintln!(
-                "An error occured while serializing {:?}:\n{}",
-                message, error
-            ),
-        }
-    }
-}
-
-/// Handle server messages which have to be sent to only one specific client
-fn handle_outgoing_messages(mut server: ResMut<renet::RenetServer>, mut events: EventReader<SM>) {
-    let channel_id = 0;
-
-    for (recipient_id, server_msg) in events.iter() {
-        match bincode::serialize(server_msg) {
-            Ok(serialized_msg) => server.send_message(*recipient_id, channel_id, serialized_msg),
             Err(error) => eprintln!(
                 "An error occured while serializing {:?}:\n{}",
                 server_msg, error
@@ -136,71 +277,328 @@ fn handle_outgoing_messages(mut server: ResMut<renet::RenetServer>, mut events:
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_server_events(
+    mut commands: Commands,
     mut server_events: EventReader<ServerEvent>,
-    mut server_broadcast_events: EventWriter<Broadcast>,
     mut server_msg_events: EventWriter<SM>,
     mut player_spawn_events: EventWriter<SpawnPlayer>,
     mut player_despawn_events: EventWriter<DespawnPlayer>,
-    players: Res<Players>,
+    mut player_resume_events: EventWriter<ResumePlayer>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+    mut players: ResMut<Players>,
+    mut held_sessions: ResMut<HeldSessions>,
+    mut next_player_id: ResMut<NextPlayerId>,
+    time: Res<Time>,
+    settings: Res<ServerSettings>,
+    shutdown_requested: Res<ShutdownRequested>,
+    transform_query: Query<&Transform>,
 ) {
     for event in server_events.iter() {
         match event {
             ServerEvent::ClientConnected(new_id, user_data) => {
-                let user_data = bincode::deserialize::<UserData>(&**user_data);
-                if user_data.is_err() {
-                    continue;
-                }
+                let user_data = parse_user_data(&**user_data);
+
+                // A client whose connection dropped takes over its old player again, once
+                // nothing else turns it away
+                let resume_token = user_data
+                    .as_ref()
+                    .ok()
+                    .and_then(|user_data| user_data.resume_token)
+                    .filter(|resume_token| has_session(*resume_token, &held_sessions, &players));
+
+                let result = if shutdown_requested.get() {
+                    Err((
+                        DisconnectKind::ServerShutdown,
+                        "The server is shutting down".to_owned(),
+                    ))
+                } else if !has_slot(
+                    resume_token.is_some(),
+                    &players,
+                    &held_sessions,
+                    settings.max_clients,
+                ) {
+                    Err((
+                        DisconnectKind::ServerFull,
+                        format!("The server is full ({} players)", settings.max_clients),
+                    ))
+                } else if let Some((player_info, old_id)) = resume_token.and_then(|resume_token| {
+                    take_session(resume_token, &mut held_sessions, &mut players)
+                }) {
+                    // The server may not have noticed that the old connection is gone
+                    if let Some(old_id) = old_id {
+                        pending_disconnects
+                            .0
+                            .insert(old_id, Timer::from_seconds(0.0, TimerMode::Once));
+                    }
+                    Ok((player_info, true))
+                } else {
+                    user_data.and_then(|user_data| {
+                        let username = user_data.username.trim();
+                        validate_username(username)
+                            .map_err(|reason| (DisconnectKind::Rejected, reason))?;
+
+                        if settings.is_banned(username) {
+                            return Err((
+                                DisconnectKind::Banned,
+                                "You are banned from this server".to_owned(),
+                            ));
+                        }
+
+                        let username = unique_username(username, |name| {
+                            players
+                                .0
+                                .values()
+                                .chain(held_sessions.0.values().map(|held| &held.player_info))
+                                .any(|info| info.username.eq_ignore_ascii_case(name))
+                        });
+
+                        let player_id = next_player_id.0;
+                        next_player_id.0 += 1;
+
+                        Ok((
+                            PlayerInfo {
+                                id: player_id,
+                                entity: commands.spawn_empty().id(),
+                                username,
+                                resume_token: 0,
+                            },
+                            false,
+                        ))
+                    })
+                };
+
+                let (mut player_info, resumed) = match result {
+                    Ok(result) => result,
+                    Err((kind, reason)) => {
+                        disconnect_client(
+                            *new_id,
+                            kind,
+                            reason,
+                            &mut server_msg_events,
+                            &mut player_despawn_events,
+                            &mut pending_disconnects,
+                        );
+                        continue;
+                    }
+                };
 
-                let user_data = user_data.unwrap();
-                let username = user_data.username.trim();
+                // Tokens are only good for a single resume
+                player_info.resume_token = rand::random();
 
-                println!("{} has joined the game as {}", new_id, user_data.username);
+                let position = if resumed {
+                    println!(
+                        "{} has taken over {} (player {})",
+                        new_id, player_info.username, player_info.id
+                    );
 
-                // Inform existing players about new player
-                server_broadcast_events.send(Broadcast {
-                    message: ServerMessage::PlayerJoined {
-                        id: *new_id,
-                        username: username.to_owned(),
-                        position: PLAYER_SPAWN,
+                    player_resume_events.send(ResumePlayer {
+                        entity: player_info.entity,
+                    });
+                    transform_query
+                        .get(player_info.entity)
+                        .map_or(settings.spawn_position(), |player_tf| {
+                            player_tf.translation.truncate()
+                        })
+                } else {
+                    println!(
+                        "{} has joined the game as {} (player {})",
+                        new_id, player_info.username, player_info.id
+                    );
+
+                    // Spawn the new player in server world
+                    player_spawn_events.send(SpawnPlayer {
+                        entity: player_info.entity,
+                        position: settings.spawn_position(),
+                    });
+                    settings.spawn_position()
+                };
+
+                // Inform the player about their identity. Nearby entities follow in the
+                // join snapshot once the player is spawned.
+                server_msg_events.send((
+                    *new_id,
+                    ServerMessage::Welcome {
+                        id: player_info.id,
+                        username: player_info.username.clone(),
+                        server_name: settings.server_name.clone(),
+                        motd: settings.motd.clone(),
+                        tick_rate: settings.tick_rate,
+                        position,
+                        resume_token: player_info.resume_token,
                     },
-                    except: Some(*new_id),
-                });
-
-                // Inform new player about existing players
-                players.0.iter().for_each(|(player_id, player_info)| {
-                    server_msg_events.send((
-                        *new_id,
-                        ServerMessage::PlayerJoined {
-                            id: *player_id,
-                            username: player_info.username.clone(),
-                            position: PLAYER_SPAWN,
-                        },
-                    ));
-                });
-
-                // Spawn the new player in server world
-                player_spawn_events.send(SpawnPlayer {
-                    id: *new_id,
-                    position: PLAYER_SPAWN,
-                    username: username.to_owned(),
-                })
+                ));
+
+                // The player is registered right away, so later joins see the name as taken
+                players.0.insert(*new_id, player_info);
             }
             ServerEvent::ClientDisconnected(id) => {
-                println!("{} has left the game", id);
-                player_despawn_events.send(DespawnPlayer { id: *id });
+                pending_disconnects.0.remove(id);
+
+                // Rejected clients never joined and dropped ones are removed already, so
+                // there is nobody to remove. Other clients despawn the player once it
+                // disappears from their area of interest.
+                if !players.0.contains_key(id) {
+                    continue;
+                }
 
-                server_broadcast_events.send(Broadcast {
-                    message: ServerMessage::PlayerLeft { id: *id },
-                    except: None,
-                });
+                if settings.resume_grace_period == 0.0 {
+                    println!("{} has left the game", id);
+                    player_despawn_events.send(DespawnPlayer { id: *id });
+                } else if let Some(player_info) = players.0.remove(id) {
+                    println!(
+                        "{} lost connection, holding {} for {}s",
+                        id, player_info.username, settings.resume_grace_period
+                    );
+
+                    let expires_at = time.elapsed_seconds_f64() + settings.resume_grace_period;
+                    held_sessions.hold(player_info, expires_at);
+                }
             }
         }
     }
 }
 
-fn velocity_system(time: Res<Time>, mut query: Query<(&mut Transform, &Velocity)>) {
+/// Tell a client why it is dropped and disconnect it shortly after. Its player is removed
+/// right away, so it can't be held for resuming.
+fn disconnect_client(
+    client_id: u64,
+    kind: DisconnectKind,
+    reason: String,
+    server_msg_events: &mut EventWriter<SM>,
+    player_despawn_events: &mut EventWriter<DespawnPlayer>,
+    pending_disconnects: &mut PendingDisconnects,
+) {
+    // Already on the way out
+    if pending_disconnects.0.contains_key(&client_id) {
+        return;
+    }
+
+    println!("Disconnecting {} ({:?}): {}", client_id, kind, reason);
+
+    server_msg_events.send((client_id, ServerMessage::Disconnected { reason, kind }));
+    player_despawn_events.send(DespawnPlayer { id: client_id });
+    pending_disconnects.0.insert(
+        client_id,
+        Timer::from_seconds(DISCONNECT_DELAY, TimerMode::Once),
+    );
+}
+
+fn parse_user_data(serialized: &[u8]) -> Result<UserData, (DisconnectKind, String)> {
+    let malformed = || (DisconnectKind::Rejected, "Malformed user data".to_owned());
+
+    match UserData::read_protocol_version(serialized) {
+        Some(PROTOCOL_VERSION) => {}
+        Some(version) => {
+            return Err((
+                DisconnectKind::VersionMismatch,
+                format!(
+                    "Incompatible game version (client protocol {}, server protocol {})",
+                    version, PROTOCOL_VERSION
+                ),
+            ))
+        }
+        None => return Err(malformed()),
+    }
+
+    wire::deserialize::<UserData>(serialized).map_err(|_| malformed())
+}
+
+fn disconnect_clients_system(
+    mut events: EventReader<DisconnectClient>,
+    mut server_msg_events: EventWriter<SM>,
+    mut player_despawn_events: EventWriter<DespawnPlayer>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+) {
+    for DisconnectClient { id, kind, reason } in events.iter() {
+        disconnect_client(
+            *id,
+            *kind,
+            reason.clone(),
+            &mut server_msg_events,
+            &mut player_despawn_events,
+            &mut pending_disconnects,
+        );
+    }
+}
+
+fn pending_disconnects_system(
+    time: Res<Time>,
+    mut server: ResMut<renet::RenetServer>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+) {
+    pending_disconnects.0.retain(|client_id, timer| {
+        if timer.tick(time.delta()).finished() {
+            server.disconnect(*client_id);
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// Once Ctrl-C is pressed, tell every client the server shuts down and exit when all of
+/// them are disconnected
+fn shutdown_system(
+    mut shutting_down: Local<bool>,
+    shutdown_requested: Res<ShutdownRequested>,
+    server: Res<renet::RenetServer>,
+    mut server_msg_events: EventWriter<SM>,
+    mut player_despawn_events: EventWriter<DespawnPlayer>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+    mut app_exit_events: EventWriter<AppExit>,
+) {
+    if !*shutting_down {
+        if !shutdown_requested.get() {
+            return;
+        }
+        *shutting_down = true;
+
+        println!("Shutting down");
+        for client_id in server.clients_id() {
+            disconnect_client(
+                client_id,
+                DisconnectKind::ServerShutdown,
+                "The server is shutting down".to_owned(),
+                &mut server_msg_events,
+                &mut player_despawn_events,
+                &mut pending_disconnects,
+            );
+        }
+    }
+
+    if pending_disconnects.0.is_empty() {
+        app_exit_events.send(AppExit);
+    }
+}
+
+fn tick_system(mut tick: ResMut<ServerTick>) {
+    tick.0 += 1;
+}
+
+/// Answer pings with the current tick, so clients can work out which tick it is
+fn ping_system(
+    mut client_msg_events: EventReader<CM>,
+    mut server_msg_events: EventWriter<SM>,
+    tick: Res<ServerTick>,
+) {
+    for (client_id, client_msg) in client_msg_events.iter() {
+        if let ClientMessage::Ping { client_time } = client_msg {
+            server_msg_events.send((
+                *client_id,
+                ServerMessage::Pong {
+                    client_time: *client_time,
+                    tick: tick.0,
+                },
+            ));
+        }
+    }
+}
+
+/// Runs on the fixed physics timestep, whose length the frame's delta time doesn't reflect
+fn velocity_system(settings: Res<ServerSettings>, mut query: Query<(&mut Transform, &Velocity)>) {
+    let timestep = settings.physics_timestep() as f32;
     for (mut tf, velocity) in query.iter_mut() {
-        tf.translation += velocity.0.extend(0.0) * time.delta_seconds();
+        tf.translation += velocity.0.extend(0.0) * timestep;
     }
 }
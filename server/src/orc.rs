@@ -1,37 +1,143 @@
-use std::f32::consts::PI;
-
 use bevy::prelude::*;
 
-use crate::{components::Velocity, Broadcast};
-use shared::ServerMessage;
+use crate::{
+    collision::{
+        events::{Collision, CollisionKind},
+        Collider, CollisionLayer,
+    },
+    components::{Health, Velocity},
+    config::ServerSettings,
+    health::Downed,
+    projectile::events::ProjectileHit,
+    Players, ServerTick,
+};
+use shared::WORLD_HALF_EXTENT;
 
 pub mod events {
-    use bevy::prelude::Vec2;
+    use bevy::prelude::{Entity, Vec2};
+
+    use super::OrcKind;
 
+    /// The orc gets the next free id
     pub struct SpawnOrc {
-        pub id: u64,
+        pub kind: OrcKind,
         pub position: Vec2,
         pub direction: f32,
     }
+
+    /// An orc landed a melee hit on a player
+    pub struct OrcAttack {
+        /// Entity of the player which was hit
+        pub target: Entity,
+        pub damage: f32,
+    }
 }
 
-const ORC_SPEED: f32 = 6000.0;
+/// Speed of a grunt chasing a player
+const ORC_SPEED: f32 = 200.0;
+const WANDER_SPEED: f32 = 60.0;
+const FLEE_SPEED: f32 = 240.0;
+const ORC_RADIUS: f32 = 24.0;
+/// How quickly orcs turn toward the velocity they want, per second
+const STEERING: f32 = 6.0;
+
+/// Players closer than this are noticed
+pub const SIGHT_RANGE: f32 = 1000.0;
+/// A chased player further away than this is given up on
+const LOSE_RANGE: f32 = 1400.0;
+const ATTACK_RANGE: f32 = 60.0;
+/// An attacking orc only goes back to chasing once its target is this much out of range
+const ATTACK_RANGE_SLACK: f32 = 1.25;
+/// Health of a grunt
+const ORC_MAX_HEALTH: f32 = 50.0;
+/// Damage of a grunt's hit
+const ATTACK_DAMAGE: f32 = 10.0;
+/// Seconds between entering attack range and the first hit
+const ATTACK_WINDUP: f64 = 0.4;
+const ATTACK_COOLDOWN: f64 = 1.0;
+/// Seconds an orc runs away after being shot
+const FLEE_DURATION: f64 = 1.5;
+/// Orcs with less than this share of their health left run away when shot
+const FLEE_HEALTH: f32 = 0.4;
+
+/// Seconds to stand around or wander in one direction, picked at random in between
+const IDLE_DURATION: (f64, f64) = (1.0, 3.0);
+const WANDER_DURATION: (f64, f64) = (1.0, 4.0);
 
 #[derive(Component)]
-struct Orc(u64);
+pub struct Orc(pub u64);
+
+#[derive(Resource, Default)]
+struct NextOrcId(u64);
+
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrcKind {
+    Grunt,
+    /// Fast but hits softly
+    Runner,
+    /// Slow but hits hard
+    Brute,
+}
+
+impl OrcKind {
+    fn chase_speed(&self) -> f32 {
+        match self {
+            OrcKind::Grunt => ORC_SPEED,
+            OrcKind::Runner => ORC_SPEED * 1.4,
+            OrcKind::Brute => ORC_SPEED * 0.7,
+        }
+    }
+
+    fn max_health(&self) -> f32 {
+        match self {
+            OrcKind::Grunt => ORC_MAX_HEALTH,
+            OrcKind::Runner => ORC_MAX_HEALTH * 0.5,
+            OrcKind::Brute => ORC_MAX_HEALTH * 3.0,
+        }
+    }
+
+    fn damage(&self) -> f32 {
+        match self {
+            OrcKind::Grunt => ATTACK_DAMAGE,
+            OrcKind::Runner => ATTACK_DAMAGE * 0.5,
+            OrcKind::Brute => ATTACK_DAMAGE * 2.5,
+        }
+    }
+}
+
+/// What an orc is up to. Ticks are server ticks.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub enum OrcState {
+    Idle { until: u64 },
+    Wander { direction: f32, until: u64 },
+    Chase { target: Entity },
+    Attack { target: Entity, next_hit: u64 },
+    Flee { from: Vec2, until: u64 },
+}
 
 pub struct OrcPlugin;
 
 impl Plugin for OrcPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<events::SpawnOrc>()
+        app.insert_resource(NextOrcId::default())
+            .add_event::<events::SpawnOrc>()
+            .add_event::<events::OrcAttack>()
             .add_system(spawn_orc_system)
-            .add_system(orc_transform_update_system);
+            .add_system(orc_flee_system)
+            .add_system(orc_collision_system)
+            .add_system(orc_bounds_system);
     }
 }
 
-fn spawn_orc_system(mut commands: Commands, mut events: EventReader<events::SpawnOrc>) {
+fn spawn_orc_system(
+    mut commands: Commands,
+    mut events: EventReader<events::SpawnOrc>,
+    mut next_id: ResMut<NextOrcId>,
+) {
     for event in events.iter() {
+        let id = next_id.0;
+        next_id.0 += 1;
+
         commands.spawn((
             TransformBundle {
                 local: Transform {
@@ -41,25 +147,268 @@ fn spawn_orc_system(mut commands: Commands, mut events: EventReader<events::Spaw
                 },
                 ..Default::default()
             },
-            Orc(event.id),
-            Velocity(Vec2::from_angle(event.direction) * ORC_SPEED),
+            Orc(id),
+            event.kind,
+            Health::new(event.kind.max_health()),
+            OrcState::Idle { until: 0 },
+            Velocity(Vec2::ZERO),
+            Collider::Circle { radius: ORC_RADIUS },
+            CollisionLayer::Orc,
         ));
     }
 }
 
-fn orc_transform_update_system(
-    mut events: EventWriter<Broadcast>,
-    query: Query<(&Transform, &Orc), Changed<Transform>>,
+/// Orcs notice players who bump into them, and stop wandering into walls
+pub fn orc_collision_system(
+    mut events: EventReader<Collision>,
+    tick: Res<ServerTick>,
+    settings: Res<ServerSettings>,
+    mut orc_query: Query<&mut OrcState>,
+    downed_query: Query<(), With<Downed>>,
+) {
+    for event in events.iter() {
+        let mut state = match orc_query.get_mut(event.a) {
+            Ok(state) => state,
+            Err(_) => continue,
+        };
+        let unaware = matches!(*state, OrcState::Idle { .. } | OrcState::Wander { .. });
+
+        match event.kind {
+            CollisionKind::OrcPlayer if unaware && !downed_query.contains(event.b) => {
+                *state = OrcState::Chase { target: event.b };
+            }
+            CollisionKind::Wall if matches!(*state, OrcState::Wander { .. }) => {
+                *state = idle(tick.0, settings.tick_rate);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Wounded orcs run away from whoever shot them
+pub fn orc_flee_system(
+    mut events: EventReader<ProjectileHit>,
+    tick: Res<ServerTick>,
+    settings: Res<ServerSettings>,
+    players: Res<Players>,
+    transform_query: Query<&Transform, Without<Orc>>,
+    mut orc_query: Query<(&Health, &mut OrcState), With<Orc>>,
+) {
+    for event in events.iter() {
+        let from = players
+            .0
+            .get(&event.shooter)
+            .and_then(|player_info| transform_query.get(player_info.entity).ok())
+            .map_or(event.position, |shooter_tf| {
+                shooter_tf.translation.truncate()
+            });
+
+        if let Ok((health, mut state)) = orc_query.get_mut(event.target) {
+            if health.fraction() < FLEE_HEALTH {
+                *state = OrcState::Flee {
+                    from,
+                    until: tick.0 + ticks(FLEE_DURATION, settings.tick_rate),
+                };
+            }
+        }
+    }
+}
+
+/// Advance every orc's state once per tick, steer it toward the velocity its state
+/// wants and land the hits of attacking orcs. Runs on the fixed physics timestep.
+pub fn orc_ai_system(
+    tick: Res<ServerTick>,
+    settings: Res<ServerSettings>,
+    players: Res<Players>,
+    mut attack_events: EventWriter<events::OrcAttack>,
+    mut orc_query: Query<(&OrcKind, &mut OrcState, &mut Velocity, &mut Transform), With<Orc>>,
+    transform_query: Query<&Transform, Without<Orc>>,
+    downed_query: Query<(), With<Downed>>,
 ) {
-    let broadcasts = query.iter().map(|(orc_tf, orc)| Broadcast {
-        message: ServerMessage::OrcTransformUpdate {
-            id: orc.0,
-            position: orc_tf.translation.truncate(),
-            rotation: orc_tf.rotation.z,
+    let dt = (1.0 / settings.tick_rate) as f32;
+    // Downed players are left alone
+    let player_positions: Vec<(Entity, Vec2)> = players
+        .0
+        .values()
+        .filter(|player_info| !downed_query.contains(player_info.entity))
+        .filter_map(|player_info| {
+            let player_tf = transform_query.get(player_info.entity).ok()?;
+            Some((player_info.entity, player_tf.translation.truncate()))
+        })
+        .collect();
+
+    for (kind, mut state, mut velocity, mut orc_tf) in orc_query.iter_mut() {
+        let position = orc_tf.translation.truncate();
+        let nearest = player_positions.iter().copied().min_by(|(_, a), (_, b)| {
+            a.distance_squared(position)
+                .total_cmp(&b.distance_squared(position))
+        });
+        let position_of = |target: Entity| {
+            player_positions
+                .iter()
+                .find(|(entity, _)| *entity == target)
+                .map_or(position, |(_, target_position)| *target_position)
+        };
+
+        *state = next_state(*state, position, nearest, tick.0, settings.tick_rate);
+
+        if let OrcState::Attack { target, next_hit } = &mut *state {
+            if tick.0 >= *next_hit {
+                attack_events.send(events::OrcAttack {
+                    target: *target,
+                    damage: kind.damage(),
+                });
+                *next_hit = tick.0 + ticks(ATTACK_COOLDOWN, settings.tick_rate);
+            }
+        }
+
+        let desired = match *state {
+            OrcState::Idle { .. } | OrcState::Attack { .. } => Vec2::ZERO,
+            OrcState::Wander { direction, .. } => Vec2::from_angle(direction) * WANDER_SPEED,
+            OrcState::Chase { target } => {
+                (position_of(target) - position).normalize_or_zero() * kind.chase_speed()
+            }
+            OrcState::Flee { from, .. } => (position - from).normalize_or_zero() * FLEE_SPEED,
+        };
+        let current = velocity.0;
+        velocity.0 = current + (desired - current) * (STEERING * dt).min(1.0);
+
+        let facing = match *state {
+            OrcState::Attack { target, .. } => position_of(target) - position,
+            _ => velocity.0,
+        };
+        if facing.length_squared() > 1.0 {
+            orc_tf.rotation = Quat::from_rotation_z(facing.y.atan2(facing.x));
+        }
+    }
+}
+
+/// The state an orc goes to from `state`, given the nearest player and where it is
+fn next_state(
+    state: OrcState,
+    position: Vec2,
+    nearest: Option<(Entity, Vec2)>,
+    tick: u64,
+    tick_rate: f64,
+) -> OrcState {
+    if let OrcState::Flee { until, .. } = state {
+        if tick < until {
+            return state;
+        }
+        return idle(tick, tick_rate);
+    }
+
+    if let Some((target, target_position)) = nearest {
+        let distance = position.distance(target_position);
+        let already_attacking =
+            matches!(state, OrcState::Attack { target: current, .. } if current == target);
+
+        if already_attacking && distance <= ATTACK_RANGE * ATTACK_RANGE_SLACK {
+            return state;
+        }
+        if distance <= ATTACK_RANGE {
+            return OrcState::Attack {
+                target,
+                next_hit: tick + ticks(ATTACK_WINDUP, tick_rate),
+            };
+        }
+
+        let engaged = matches!(state, OrcState::Chase { .. } | OrcState::Attack { .. });
+        if distance <= if engaged { LOSE_RANGE } else { SIGHT_RANGE } {
+            return OrcState::Chase { target };
+        }
+    }
+
+    match state {
+        OrcState::Idle { until } if tick >= until => OrcState::Wander {
+            direction: rand::random::<f32>() * std::f32::consts::TAU,
+            until: tick + ticks(random_between(WANDER_DURATION), tick_rate),
         },
-        except: None,
-    });
+        OrcState::Wander { until, .. } if tick >= until => idle(tick, tick_rate),
+        OrcState::Idle { .. } | OrcState::Wander { .. } => state,
+        // Nobody left to go after
+        _ => idle(tick, tick_rate),
+    }
+}
 
-    // Send all transform update broadcasts together
-    events.send_batch(broadcasts);
+fn idle(tick: u64, tick_rate: f64) -> OrcState {
+    OrcState::Idle {
+        until: tick + ticks(random_between(IDLE_DURATION), tick_rate),
+    }
+}
+
+fn ticks(seconds: f64, tick_rate: f64) -> u64 {
+    (seconds * tick_rate).round() as u64
+}
+
+fn random_between((min, max): (f64, f64)) -> f64 {
+    min + rand::random::<f64>() * (max - min)
+}
+
+/// Orcs which leave the world can't be sent to clients anymore
+fn orc_bounds_system(mut commands: Commands, query: Query<(Entity, &Transform), With<Orc>>) {
+    for (entity, orc_tf) in query.iter() {
+        if orc_tf.translation.truncate().abs().max_element() > WORLD_HALF_EXTENT {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_RATE: f64 = 60.0;
+
+    fn player() -> Entity {
+        Entity::from_raw(1)
+    }
+
+    #[test]
+    fn orcs_chase_players_in_sight_and_attack_in_range() {
+        let idle = OrcState::Idle { until: 100 };
+        let far = Some((player(), Vec2::new(SIGHT_RANGE + 10.0, 0.0)));
+        let near = Some((player(), Vec2::new(SIGHT_RANGE - 10.0, 0.0)));
+        let close = Some((player(), Vec2::new(ATTACK_RANGE - 10.0, 0.0)));
+
+        assert_eq!(next_state(idle, Vec2::ZERO, far, 0, TICK_RATE), idle);
+
+        let chase = next_state(idle, Vec2::ZERO, near, 0, TICK_RATE);
+        assert_eq!(chase, OrcState::Chase { target: player() });
+        // Chased players have to get further away to be lost
+        assert_eq!(next_state(chase, Vec2::ZERO, far, 1, TICK_RATE), chase);
+
+        let attack = next_state(chase, Vec2::ZERO, close, 10, TICK_RATE);
+        assert_eq!(
+            attack,
+            OrcState::Attack {
+                target: player(),
+                next_hit: 10 + ticks(ATTACK_WINDUP, TICK_RATE),
+            }
+        );
+        let just_out_of_range = Some((player(), Vec2::new(ATTACK_RANGE + 5.0, 0.0)));
+        assert_eq!(
+            next_state(attack, Vec2::ZERO, just_out_of_range, 11, TICK_RATE),
+            attack
+        );
+    }
+
+    #[test]
+    fn orcs_flee_until_the_time_is_up() {
+        let flee = OrcState::Flee {
+            from: Vec2::ZERO,
+            until: 100,
+        };
+        let close = Some((player(), Vec2::new(10.0, 0.0)));
+
+        assert_eq!(next_state(flee, Vec2::ZERO, close, 99, TICK_RATE), flee);
+        assert!(matches!(
+            next_state(flee, Vec2::ZERO, close, 100, TICK_RATE),
+            OrcState::Idle { .. }
+        ));
+        assert!(matches!(
+            next_state(flee, Vec2::ZERO, None, 100, TICK_RATE),
+            OrcState::Idle { .. }
+        ));
+    }
 }
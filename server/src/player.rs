@@ -1,64 +1,136 @@
 use bevy::prelude::*;
 
-use crate::{orc::events::*, Broadcast, PlayerInfo, Players, CM};
-use shared::*;
+use crate::{
+    collision::{Collider, CollisionLayer},
+    components::Health,
+    config::ServerSettings,
+    health::Downed,
+    lag_compensation::ShotBudget,
+    DisconnectClient, Players, CM, SM,
+};
+use shared::{
+    movement::{move_player, INPUT_TIMESTEP, MAX_INPUTS_PER_MESSAGE},
+    *,
+};
 
 pub mod events {
-    use bevy::prelude::Vec2;
+    use bevy::prelude::{Entity, Vec2};
 
+    /// Fills in the entity reserved for a player once they were accepted
     pub struct SpawnPlayer {
-        pub id: u64,
+        pub entity: Entity,
         pub position: Vec2,
-        pub username: String,
     }
 
     pub struct DespawnPlayer {
         pub id: u64,
     }
+
+    /// A held player was taken over by a new connection, whose inputs start over
+    pub struct ResumePlayer {
+        pub entity: Entity,
+    }
 }
 
 #[derive(Component)]
 struct Player;
 
+/// The newest input applied to a player
+#[derive(Component, Default)]
+struct LastInput {
+    sequence: u32,
+    aim: f32,
+}
+
+/// Inputs a player may still apply. Refills in real time, so sending inputs faster than
+/// they are produced doesn't make a player faster.
+#[derive(Component, Default)]
+struct InputBudget {
+    available: f32,
+    last_refill: Option<f64>,
+}
+
+impl InputBudget {
+    fn refill(&mut self, now: f64) {
+        if let Some(last_refill) = self.last_refill {
+            let earned = (now - last_refill) as f32 / INPUT_TIMESTEP;
+            self.available = (self.available + earned).min(MAX_INPUT_BUDGET);
+        } else {
+            self.available = MAX_INPUT_BUDGET;
+        }
+
+        self.last_refill = Some(now);
+    }
+
+    /// Use up one input, if there is one left
+    fn take(&mut self) -> bool {
+        if self.available < 1.0 {
+            return false;
+        }
+
+        self.available -= 1.0;
+        true
+    }
+}
+
+/// Recent invalid movement. Decays over time, so only repeat offenders get kicked.
+#[derive(Component, Default)]
+struct MovementViolations(f32);
+
+impl MovementViolations {
+    /// Count a message with rejected inputs. Returns whether the player is over `limit`.
+    fn record(&mut self, limit: u32) -> bool {
+        self.0 += 1.0;
+        self.0 > limit as f32
+    }
+
+    fn decay(&mut self, seconds: f32) {
+        self.0 = (self.0 - VIOLATION_DECAY_PER_SECOND * seconds).max(0.0);
+    }
+}
+
+/// Allows a burst of inputs which were delayed by the network to still be applied
+const MAX_INPUT_BUDGET: f32 = MAX_INPUTS_PER_MESSAGE as f32 * 2.0;
+const VIOLATION_DECAY_PER_SECOND: f32 = 0.5;
+const PLAYER_MAX_HEALTH: f32 = 100.0;
+pub const PLAYER_RADIUS: f32 = 24.0;
+
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<events::SpawnPlayer>()
             .add_event::<events::DespawnPlayer>()
+            .add_event::<events::ResumePlayer>()
             .add_system(spawn_player_system)
             .add_system(despawn_player_system)
-            .add_system(player_transform_update_system)
-            .add_system(player_shoot_system);
+            .add_system(resume_player_system)
+            .add_system(player_input_system)
+            .add_system(movement_violation_decay_system);
     }
 }
 
-fn spawn_player_system(
-    mut commands: Commands,
-    mut events: EventReader<events::SpawnPlayer>,
-    mut players: ResMut<Players>,
-) {
+fn spawn_player_system(mut commands: Commands, mut events: EventReader<events::SpawnPlayer>) {
     for event in events.iter() {
-        let entity = commands
-            .spawn((
-                TransformBundle {
-                    local: Transform {
-                        translation: event.position.extend(0.0),
-                        ..Default::default()
-                    },
+        commands.entity(event.entity).insert((
+            TransformBundle {
+                local: Transform {
+                    translation: event.position.extend(0.0),
                     ..Default::default()
                 },
-                Player,
-            ))
-            .id();
-
-        players.0.insert(
-            event.id,
-            PlayerInfo {
-                entity,
-                username: event.username.clone(),
+                ..Default::default()
             },
-        );
+            Player,
+            LastInput::default(),
+            InputBudget::default(),
+            MovementViolations::default(),
+            ShotBudget::default(),
+            Health::new(PLAYER_MAX_HEALTH),
+            Collider::Circle {
+                radius: PLAYER_RADIUS,
+            },
+            CollisionLayer::Player,
+        ));
     }
 }
 
@@ -74,78 +146,161 @@ fn despawn_player_system(
     }
 }
 
-fn player_transform_update_system(
+fn resume_player_system(
+    mut events: EventReader<events::ResumePlayer>,
+    mut query: Query<(&mut LastInput, &mut InputBudget)>,
+) {
+    for event in events.iter() {
+        if let Ok((mut last_input, mut budget)) = query.get_mut(event.entity) {
+            last_input.sequence = 0;
+            *budget = InputBudget::default();
+        }
+    }
+}
+
+/// What applying a player's inputs touches
+type InputQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static mut Transform,
+        &'static mut LastInput,
+        &'static mut InputBudget,
+        &'static mut MovementViolations,
+        Option<&'static Downed>,
+    ),
+    With<Player>,
+>;
+
+fn player_input_system(
+    time: Res<Time>,
     mut client_msg_events: EventReader<CM>,
-    mut server_broadcast_events: EventWriter<Broadcast>,
-    mut query: Query<&mut Transform, With<Player>>,
+    mut server_msg_events: EventWriter<SM>,
+    mut disconnect_events: EventWriter<DisconnectClient>,
+    mut query: InputQuery,
     players: Res<Players>,
+    settings: Res<ServerSettings>,
 ) {
     for client_msg in client_msg_events.iter() {
-        if let (player_id, ClientMessage::PlayerTransformUpdate { position, rotation }) = client_msg
-        {
-            let player_info = players.0.get(player_id);
+        if let (client_id, ClientMessage::PlayerInput { inputs }) = client_msg {
+            let player_info = players.0.get(client_id);
             if player_info.is_none() {
                 continue;
             }
 
             let player_info = player_info.unwrap();
-            let mut player_tf = query.get_mut(player_info.entity).unwrap();
+            let (mut player_tf, mut last_input, mut budget, mut violations, downed) =
+                match query.get_mut(player_info.entity) {
+                    Ok(player) => player,
+                    // Components of a player who just joined are not inserted yet
+                    Err(_) => continue,
+                };
+
+            // Inputs are repeated across messages, only apply the ones we haven't seen
+            let new_inputs: Vec<_> = inputs
+                .iter()
+                .filter(|(sequence, _)| *sequence > last_input.sequence)
+                .collect();
+
+            if new_inputs.is_empty() {
+                continue;
+            }
+
+            budget.refill(time.elapsed_seconds_f64());
+
+            let mut position = player_tf.translation.truncate();
+            let mut rejected = 0;
+
+            for (sequence, input) in new_inputs {
+                // Rejected inputs still count as seen, so the client drops them when
+                // it receives the ack and snaps back to the server's position
+                last_input.sequence = *sequence;
+
+                // Downed players stay where they fell
+                if downed.is_some() {
+                    continue;
+                }
+
+                if !input.aim.is_finite() || !budget.take() {
+                    rejected += 1;
+                    continue;
+                }
+
+                position = move_player(position, input);
+                last_input.aim = input.aim;
+            }
+
+            if rejected > 0 && violations.record(settings.movement_violation_limit) {
+                disconnect_events.send(DisconnectClient {
+                    id: *client_id,
+                    kind: DisconnectKind::Kicked,
+                    reason: "Kicked for invalid movement".to_owned(),
+                });
+            }
 
             // Update server player info
             player_tf.translation = position.extend(0.0);
-            player_tf.rotation = Quat::from_rotation_z(*rotation);
+            player_tf.rotation = Quat::from_rotation_z(last_input.aim);
 
-            server_broadcast_events.send(Broadcast {
-                message: ServerMessage::PlayerTransformUpdate {
-                    id: *player_id,
-                    position: *position,
-                    rotation: *rotation,
+            server_msg_events.send((
+                *client_id,
+                ServerMessage::PlayerInputAck {
+                    sequence: last_input.sequence,
+                    position,
                 },
-                except: Some(*player_id),
-            });
+            ));
         }
     }
 }
 
-fn player_shoot_system(
-    mut client_msg_events: EventReader<CM>,
-    mut server_broadcast_events: EventWriter<Broadcast>,
-    mut spawn_orc_events: EventWriter<SpawnOrc>,
-    query: Query<&Transform, With<Player>>,
-    players: Res<Players>,
-) {
-    // TODO: Shoot cooldown
+fn movement_violation_decay_system(time: Res<Time>, mut query: Query<&mut MovementViolations>) {
+    for mut violations in query.iter_mut() {
+        if violations.0 > 0.0 {
+            violations.decay(time.delta_seconds());
+        }
+    }
+}
 
-    for (player_id, client_msg) in client_msg_events.iter() {
-        if let ClientMessage::Shoot { direction } = client_msg {
-            let player_info = players.0.get(player_id);
-            if player_info.is_none() {
-                continue;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let player_info = player_info.unwrap();
-            let player_tf = query.get(player_info.entity).unwrap();
-            let orc_position = player_tf.translation.truncate();
-            let direction = *direction;
-
-            let orc_id = rand::random::<u64>();
-
-            // Spawn orc in server world
-            spawn_orc_events.send(SpawnOrc {
-                id: orc_id,
-                position: orc_position,
-                direction,
-            });
-
-            // Broadcast spawn orc message
-            server_broadcast_events.send(Broadcast {
-                message: ServerMessage::SpawnOrc {
-                    id: orc_id,
-                    position: orc_position,
-                    direction,
-                },
-                except: None,
-            });
+    #[test]
+    fn input_budget_allows_a_burst_then_refills_in_real_time() {
+        let mut budget = InputBudget::default();
+        budget.refill(10.0);
+
+        for _ in 0..MAX_INPUT_BUDGET as usize {
+            assert!(budget.take());
         }
+        assert!(!budget.take());
+
+        // Three inputs worth of time later, three more go through
+        budget.refill(10.0 + 3.5 * INPUT_TIMESTEP as f64);
+        for _ in 0..3 {
+            assert!(budget.take());
+        }
+        assert!(!budget.take());
+
+        // Idling doesn't save up more than a burst
+        budget.refill(1000.0);
+        assert_eq!(budget.available, MAX_INPUT_BUDGET);
+    }
+
+    #[test]
+    fn repeat_offenders_get_kicked_unless_violations_decay() {
+        let limit = 3;
+        let mut violations = MovementViolations::default();
+
+        for _ in 0..limit {
+            assert!(!violations.record(limit));
+        }
+        assert!(violations.record(limit));
+
+        violations.decay(2.0 / VIOLATION_DECAY_PER_SECOND);
+        assert!(!violations.record(limit));
+
+        violations.decay(1000.0);
+        assert_eq!(violations.0, 0.0);
     }
 }
@@ -0,0 +1,249 @@
+use bevy::prelude::*;
+
+use crate::{
+    collision::{
+        events::{Collision, CollisionKind},
+        Collider, CollisionLayer,
+    },
+    components::Velocity,
+    config::ServerSettings,
+    lag_compensation::events::ShotFired,
+    orc::Orc,
+    Players, ServerTick, SM,
+};
+use shared::{snapshot::NetId, *};
+
+pub mod events {
+    use bevy::prelude::{Entity, Vec2};
+
+    /// A projectile reached what it hit
+    pub struct ProjectileHit {
+        /// Client id of the shooter
+        pub shooter: u64,
+        pub target: Entity,
+        pub position: Vec2,
+    }
+}
+
+const PROJECTILE_RADIUS: f32 = 4.0;
+
+#[derive(Component)]
+pub struct Projectile {
+    pub id: u64,
+    /// Client id of the shooter
+    shooter: u64,
+    /// Hit by the shot where the shooter saw it, once the projectile gets there
+    target: Option<NetId>,
+    /// Server tick at which the projectile is removed
+    expires_at: u64,
+    /// Tick at which the projectile ends on the timeline it was sent with
+    end_tick: u64,
+    /// Clients which were told about the projectile
+    recipients: Vec<u64>,
+}
+
+#[derive(Resource, Default)]
+struct NextProjectileId(u64);
+
+pub struct ProjectilePlugin;
+
+impl Plugin for ProjectilePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(NextProjectileId::default())
+            .add_event::<events::ProjectileHit>()
+            .add_system(spawn_projectile_system)
+            .add_system(projectile_collision_system)
+            .add_system(projectile_expiry_system.after(projectile_collision_system));
+    }
+}
+
+/// Fire a projectile for every shot. It stops where the shot hit, which clients learn
+/// from its despawn.
+#[allow(clippy::too_many_arguments)]
+fn spawn_projectile_system(
+    mut commands: Commands,
+    mut events: EventReader<ShotFired>,
+    mut server_msg_events: EventWriter<SM>,
+    mut next_id: ResMut<NextProjectileId>,
+    tick: Res<ServerTick>,
+    settings: Res<ServerSettings>,
+    players: Res<Players>,
+    transform_query: Query<&Transform>,
+) {
+    for event in events.iter() {
+        let owner = match players.0.get(&event.shooter) {
+            Some(player_info) => player_info.id,
+            None => continue,
+        };
+
+        let id = next_id.0;
+        next_id.0 += 1;
+
+        let direction = Vec2::from_angle(event.direction);
+        let end = event.origin + direction * event.range;
+        let flight_ticks =
+            (event.range / PROJECTILE_SPEED * settings.tick_rate as f32).ceil() as u64;
+
+        // Everyone who could see any part of the flight
+        let recipients: Vec<u64> = players
+            .0
+            .iter()
+            .filter(|(_, player_info)| {
+                transform_query
+                    .get(player_info.entity)
+                    .is_ok_and(|player_tf| {
+                        segment_distance(event.origin, end, player_tf.translation.truncate())
+                            <= settings.interest_radius
+                    })
+            })
+            .map(|(client_id, _)| *client_id)
+            .collect();
+
+        for client_id in &recipients {
+            server_msg_events.send((
+                *client_id,
+                ServerMessage::SpawnProjectile {
+                    id,
+                    owner,
+                    tick: event.tick,
+                    position: event.origin,
+                    direction: event.direction,
+                },
+            ));
+        }
+
+        commands.spawn((
+            TransformBundle {
+                local: Transform {
+                    translation: event.origin.extend(0.0),
+                    rotation: Quat::from_rotation_z(event.direction),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            Projectile {
+                id,
+                shooter: event.shooter,
+                target: event.target,
+                expires_at: tick.0 + flight_ticks,
+                end_tick: event.tick + flight_ticks,
+                recipients,
+            },
+            Velocity(direction * PROJECTILE_SPEED),
+            Collider::Circle {
+                radius: PROJECTILE_RADIUS,
+            },
+            CollisionLayer::Projectile,
+        ));
+    }
+}
+
+/// Projectiles stop at the first wall or orc they touch. An orc which walked into the
+/// line of fire since the shooter saw the world takes the hit instead of the target.
+fn projectile_collision_system(
+    mut events: EventReader<Collision>,
+    mut hit_events: EventWriter<events::ProjectileHit>,
+    tick: Res<ServerTick>,
+    mut query: Query<(&mut Projectile, &Transform)>,
+) {
+    for event in events.iter() {
+        if !matches!(
+            event.kind,
+            CollisionKind::ProjectileOrc | CollisionKind::Wall
+        ) {
+            continue;
+        }
+
+        let (mut projectile, projectile_tf) = match query.get_mut(event.a) {
+            Ok(projectile) => projectile,
+            Err(_) => continue,
+        };
+        // Already stopped by something else
+        if tick.0 >= projectile.expires_at {
+            continue;
+        }
+
+        // Its timeline is cut short by as much as its flight
+        projectile.end_tick -= projectile.expires_at - tick.0;
+        projectile.expires_at = tick.0;
+        projectile.target = None;
+
+        if event.kind == CollisionKind::ProjectileOrc {
+            hit_events.send(events::ProjectileHit {
+                shooter: projectile.shooter,
+                target: event.b,
+                position: projectile_tf.translation.truncate(),
+            });
+        }
+    }
+}
+
+/// Remove projectiles at the end of their flight. One which got to its target without
+/// being stopped hits it where the shooter saw it.
+fn projectile_expiry_system(
+    mut commands: Commands,
+    mut server_msg_events: EventWriter<SM>,
+    mut hit_events: EventWriter<events::ProjectileHit>,
+    tick: Res<ServerTick>,
+    players: Res<Players>,
+    query: Query<(Entity, &Projectile, &Transform)>,
+    orc_query: Query<(Entity, &Orc)>,
+) {
+    for (entity, projectile, projectile_tf) in query.iter() {
+        if tick.0 < projectile.expires_at {
+            continue;
+        }
+
+        if let Some(NetId::Orc(orc_id)) = projectile.target {
+            if let Some((target, _)) = orc_query.iter().find(|(_, orc)| orc.0 == orc_id) {
+                hit_events.send(events::ProjectileHit {
+                    shooter: projectile.shooter,
+                    target,
+                    position: projectile_tf.translation.truncate(),
+                });
+            }
+        }
+
+        for client_id in &projectile.recipients {
+            if players.0.contains_key(client_id) {
+                server_msg_events.send((
+                    *client_id,
+                    ServerMessage::DespawnProjectile {
+                        id: projectile.id,
+                        tick: projectile.end_tick,
+                    },
+                ));
+            }
+        }
+
+        commands.entity(entity).despawn();
+    }
+}
+
+/// Distance from `point` to the closest point of the segment from `a` to `b`
+pub fn segment_distance(a: Vec2, b: Vec2, point: Vec2) -> f32 {
+    let segment = b - a;
+    let t = if segment == Vec2::ZERO {
+        0.0
+    } else {
+        ((point - a).dot(segment) / segment.length_squared()).clamp(0.0, 1.0)
+    };
+
+    point.distance(a + segment * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_distance_clamps_to_the_ends() {
+        let a = Vec2::ZERO;
+        let b = Vec2::new(100.0, 0.0);
+
+        assert_eq!(segment_distance(a, b, Vec2::new(50.0, 30.0)), 30.0);
+        assert_eq!(segment_distance(a, b, Vec2::new(-30.0, 40.0)), 50.0);
+        assert_eq!(segment_distance(a, b, Vec2::new(130.0, 0.0)), 30.0);
+        assert_eq!(segment_distance(a, a, Vec2::new(3.0, 4.0)), 5.0);
+    }
+}
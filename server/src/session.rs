@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{PlayerInfo, Players};
+
+/// Player of a dropped connection, waiting for its client to come back
+pub struct HeldSession {
+    pub player_info: PlayerInfo,
+    /// Elapsed server time at which the player is removed for good
+    pub expires_at: f64,
+}
+
+/// Held players keyed by their resume token
+#[derive(Resource, Default)]
+pub struct HeldSessions(pub HashMap<u64, HeldSession>);
+
+impl HeldSessions {
+    pub fn hold(&mut self, player_info: PlayerInfo, expires_at: f64) {
+        self.0.insert(
+            player_info.resume_token,
+            HeldSession {
+                player_info,
+                expires_at,
+            },
+        );
+    }
+
+    /// Remove the players whose clients didn't come back by `now`
+    pub fn expire(&mut self, now: f64) -> Vec<PlayerInfo> {
+        let expired: Vec<u64> = self
+            .0
+            .iter()
+            .filter(|(_, session)| session.expires_at <= now)
+            .map(|(resume_token, _)| *resume_token)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|resume_token| self.0.remove(&resume_token))
+            .map(|session| session.player_info)
+            .collect()
+    }
+}
+
+/// Whether a resume token belongs to a held player or one which is still connected
+pub fn has_session(resume_token: u64, held_sessions: &HeldSessions, players: &Players) -> bool {
+    held_sessions.0.contains_key(&resume_token)
+        || players
+            .0
+            .values()
+            .any(|player_info| player_info.resume_token == resume_token)
+}
+
+/// Take the player a resume token belongs to away from its old connection. Also returns
+/// the client id of that connection if the server hasn't noticed yet that it is gone.
+pub fn take_session(
+    resume_token: u64,
+    held_sessions: &mut HeldSessions,
+    players: &mut Players,
+) -> Option<(PlayerInfo, Option<u64>)> {
+    if let Some(held_session) = held_sessions.0.remove(&resume_token) {
+        return Some((held_session.player_info, None));
+    }
+
+    let old_id = players
+        .0
+        .iter()
+        .find(|(_, player_info)| player_info.resume_token == resume_token)
+        .map(|(old_id, _)| *old_id)?;
+    let player_info = players.0.remove(&old_id)?;
+
+    Some((player_info, Some(old_id)))
+}
+
+/// Whether a connecting client gets a player. Held players keep their slot until they
+/// expire, and a resuming client takes over the slot of its old player.
+pub fn has_slot(
+    resuming: bool,
+    players: &Players,
+    held_sessions: &HeldSessions,
+    max_clients: usize,
+) -> bool {
+    resuming || players.0.len() + held_sessions.0.len() < max_clients
+}
+
+pub struct SessionPlugin;
+
+impl Plugin for SessionPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(HeldSessions::default())
+            .add_system(expire_sessions_system);
+    }
+}
+
+fn expire_sessions_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut held_sessions: ResMut<HeldSessions>,
+) {
+    for player_info in held_sessions.expire(time.elapsed_seconds_f64()) {
+        println!("{} did not come back", player_info.username);
+        commands.entity(player_info.entity).despawn();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(id: u64, resume_token: u64) -> PlayerInfo {
+        PlayerInfo {
+            id,
+            entity: Entity::from_raw(id as u32),
+            username: format!("player{}", id),
+            resume_token,
+        }
+    }
+
+    #[test]
+    fn held_players_expire_after_the_grace_period() {
+        let mut held_sessions = HeldSessions::default();
+        held_sessions.hold(player(1, 11), 10.0);
+        held_sessions.hold(player(2, 22), 20.0);
+
+        assert!(held_sessions.expire(9.9).is_empty());
+
+        let expired = held_sessions.expire(10.0);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, 1);
+
+        let mut players = Players::default();
+        assert!(take_session(11, &mut held_sessions, &mut players).is_none());
+        assert!(take_session(22, &mut held_sessions, &mut players).is_some());
+    }
+
+    #[test]
+    fn resume_tokens_work_once() {
+        let mut held_sessions = HeldSessions::default();
+        let mut players = Players::default();
+        held_sessions.hold(player(1, 11), 10.0);
+
+        let (player_info, old_id) = take_session(11, &mut held_sessions, &mut players).unwrap();
+        assert_eq!((player_info.id, old_id), (1, None));
+
+        assert!(take_session(11, &mut held_sessions, &mut players).is_none());
+    }
+
+    #[test]
+    fn resuming_takes_over_a_player_which_is_still_connected() {
+        let mut held_sessions = HeldSessions::default();
+        let mut players = Players::default();
+        players.0.insert(100, player(1, 11));
+        players.0.insert(200, player(2, 22));
+
+        assert!(has_session(22, &held_sessions, &players));
+        let (player_info, old_id) = take_session(22, &mut held_sessions, &mut players).unwrap();
+        assert_eq!((player_info.id, old_id), (2, Some(200)));
+        assert!(!players.0.contains_key(&200));
+
+        assert!(!has_session(33, &held_sessions, &players));
+        assert!(take_session(33, &mut held_sessions, &mut players).is_none());
+        assert_eq!(players.0.len(), 1);
+    }
+
+    #[test]
+    fn resuming_clients_join_a_full_server() {
+        let mut held_sessions = HeldSessions::default();
+        let mut players = Players::default();
+        players.0.insert(100, player(1, 11));
+        held_sessions.hold(player(2, 22), 10.0);
+
+        assert!(!has_slot(false, &players, &held_sessions, 2));
+        assert!(has_slot(false, &players, &held_sessions, 3));
+
+        // Checked before the session is taken, so a rejected client leaves it alone
+        assert!(has_session(22, &held_sessions, &players));
+        assert!(has_slot(true, &players, &held_sessions, 2));
+        assert!(held_sessions.0.contains_key(&22));
+    }
+}
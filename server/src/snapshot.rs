@@ -0,0 +1,296 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::*;
+
+use crate::{
+    bandwidth::BandwidthStats, components::Health, config::ServerSettings,
+    interest::ClientInterests, orc::Orc, Players, ServerTick, CM, SM,
+};
+use shared::{
+    snapshot::{apply_delta, encode_delta, EntityDelta, EntityState, NetId, WorldState},
+    *,
+};
+
+/// Server ticks between two state snapshots
+const SNAPSHOT_INTERVAL_TICKS: u64 = 2;
+/// State snapshots remembered per client as possible baselines
+const SNAPSHOT_HISTORY: usize = 32;
+
+#[derive(Default)]
+struct ClientSnapshots {
+    sent: VecDeque<(u64, WorldState)>,
+    /// Newest snapshot the client confirmed receiving
+    acked: Option<u64>,
+    /// Accumulated priority of entities with changes the client hasn't been sent yet
+    priorities: HashMap<NetId, f32>,
+}
+
+/// Snapshot history keyed by client id
+#[derive(Resource, Default)]
+struct SnapshotHistory(HashMap<u64, ClientSnapshots>);
+
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SnapshotHistory::default())
+            .add_system(snapshot_ack_system)
+            .add_system(state_snapshot_system);
+    }
+}
+
+/// Angle around the z axis of a 2D rotation
+pub fn rotation_angle(rotation: Quat) -> f32 {
+    rotation.to_euler(EulerRot::XYZ).2
+}
+
+fn snapshot_ack_system(
+    mut client_msg_events: EventReader<CM>,
+    mut history: ResMut<SnapshotHistory>,
+) {
+    for (client_id, client_msg) in client_msg_events.iter() {
+        if let ClientMessage::SnapshotAck { tick } = client_msg {
+            if let Some(client) = history.0.get_mut(client_id) {
+                // Acks travel unreliably, so an older one may arrive late
+                if client.acked.is_none_or(|acked| *tick > acked) {
+                    client.acked = Some(*tick);
+                }
+            }
+        }
+    }
+}
+
+/// How fast the priority of an entity with pending changes grows, per second
+fn priority_rate(id: &NetId, distance: f32, interest_radius: f32) -> f32 {
+    let importance = match id {
+        NetId::Player(_) => 2.0,
+        NetId::Orc(_) => 1.0,
+    };
+    // Entities at the edge of the area of interest grow a quarter as fast as close ones
+    let proximity = 1.0 - 0.75 * (distance / interest_radius).min(1.0);
+
+    importance * proximity
+}
+
+/// Pick the updates which fit into `budget` bytes on top of `size`, most urgent first. The
+/// priority of every update grows by `increase`, and the ones left out keep theirs for the
+/// next snapshot, so distant entities still get their turn.
+fn fit_updates(
+    updates: Vec<EntityDelta>,
+    priorities: &HashMap<NetId, f32>,
+    mut size: u64,
+    budget: u64,
+    increase: impl Fn(&NetId) -> f32,
+) -> (Vec<EntityDelta>, HashMap<NetId, f32>) {
+    // Entities without pending changes drop back to no priority
+    let mut remaining = HashMap::new();
+    let mut pending: Vec<(f32, EntityDelta)> = updates
+        .into_iter()
+        .map(|delta| {
+            let priority = priorities.get(&delta.id).copied().unwrap_or(0.0) + increase(&delta.id);
+
+            remaining.insert(delta.id, priority);
+            (priority, delta)
+        })
+        .collect();
+    pending.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+    let mut sent_updates = Vec::new();
+
+    for (_, delta) in pending {
+        let delta_size = wire::serialized_size(&delta).unwrap_or(0);
+
+        // Always send something, so a tiny budget doesn't stall every update
+        if size + delta_size > budget && !sent_updates.is_empty() {
+            continue;
+        }
+
+        size += delta_size;
+        remaining.remove(&delta.id);
+        sent_updates.push(delta);
+    }
+
+    (sent_updates, remaining)
+}
+
+/// Send every client the state of the entities it knows about as a delta against the last
+/// snapshot it acknowledged, or in full if that snapshot is unknown. Updates which don't
+/// fit into the client's bandwidth budget wait for a later snapshot, most urgent first.
+#[allow(clippy::too_many_arguments)]
+fn state_snapshot_system(
+    mut last_snapshot_tick: Local<u64>,
+    mut server_msg_events: EventWriter<SM>,
+    mut history: ResMut<SnapshotHistory>,
+    mut bandwidth_stats: ResMut<BandwidthStats>,
+    interests: Res<ClientInterests>,
+    tick: Res<ServerTick>,
+    players: Res<Players>,
+    settings: Res<ServerSettings>,
+    transform_query: Query<&Transform>,
+    health_query: Query<&Health>,
+    orc_query: Query<(&Transform, &Health, &Orc)>,
+) {
+    if tick.0 < *last_snapshot_tick + SNAPSHOT_INTERVAL_TICKS {
+        return;
+    }
+    *last_snapshot_tick = tick.0;
+
+    let player_states = players.0.values().filter_map(|player_info| {
+        let player_tf = transform_query.get(player_info.entity).ok()?;
+        let health = health_query.get(player_info.entity).ok()?;
+        Some((
+            NetId::Player(player_info.id),
+            entity_state(player_tf, health),
+        ))
+    });
+    let orc_states = orc_query
+        .iter()
+        .map(|(orc_tf, health, orc)| (NetId::Orc(orc.0), entity_state(orc_tf, health)));
+    let world: WorldState = player_states.chain(orc_states).collect();
+
+    let interval = SNAPSHOT_INTERVAL_TICKS as f32 * settings.physics_timestep() as f32;
+    let budget = (settings.snapshot_bandwidth as f32 * interval) as u64;
+
+    history
+        .0
+        .retain(|client_id, _| players.0.contains_key(client_id));
+
+    for (client_id, player_info) in players.0.iter() {
+        // Clients without a join snapshot have nothing to update yet
+        let (known, center) = match (
+            interests.0.get(client_id),
+            transform_query.get(player_info.entity),
+        ) {
+            (Some(known), Ok(player_tf)) => (known, player_tf.translation.truncate()),
+            _ => continue,
+        };
+        let state: WorldState = world
+            .iter()
+            .filter(|(id, _)| known.contains(id))
+            .map(|(id, state)| (*id, *state))
+            .collect();
+
+        let client = history.0.entry(*client_id).or_default();
+        let baseline = client.acked.and_then(|acked| {
+            client
+                .sent
+                .iter()
+                .find(|(sent_tick, _)| *sent_tick == acked)
+        });
+
+        let baseline_state = baseline.map(|(_, state)| state);
+        let (updates, removed) = encode_delta(baseline_state, &state);
+
+        let baseline_tick = baseline.map(|(baseline_tick, _)| *baseline_tick);
+        let header_size = wire::serialized_size(&ServerMessage::StateSnapshot {
+            tick: tick.0,
+            baseline: baseline_tick,
+            updates: Vec::new(),
+            removed: removed.clone(),
+        })
+        .unwrap_or(0);
+        let pending = updates.len();
+        let (sent_updates, priorities) =
+            fit_updates(updates, &client.priorities, header_size, budget, |id| {
+                let distance = state[id].position.distance(center);
+                priority_rate(id, distance, settings.interest_radius) * interval
+            });
+        let deferred = pending - sent_updates.len();
+        client.priorities = priorities;
+
+        // What the client has once it applies this snapshot, the baseline for later ones
+        let sent_state = match apply_delta(baseline_state, &sent_updates, &removed) {
+            Some(sent_state) => sent_state,
+            None => continue,
+        };
+
+        server_msg_events.send((
+            *client_id,
+            ServerMessage::StateSnapshot {
+                tick: tick.0,
+                baseline: baseline_tick,
+                updates: sent_updates,
+                removed,
+            },
+        ));
+
+        bandwidth_stats
+            .0
+            .entry(*client_id)
+            .or_default()
+            .deferred_updates = deferred;
+
+        client.sent.push_back((tick.0, sent_state));
+        if client.sent.len() > SNAPSHOT_HISTORY {
+            client.sent.pop_front();
+        }
+    }
+}
+
+fn entity_state(transform: &Transform, health: &Health) -> EntityState {
+    EntityState {
+        position: transform.translation.truncate(),
+        rotation: rotation_angle(transform.rotation),
+        health: health.fraction(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moved(id: NetId) -> EntityDelta {
+        EntityDelta {
+            id,
+            position: Some(Vec2::new(1.0, 2.0)),
+            rotation: Some(0.5),
+            health: None,
+        }
+    }
+
+    #[test]
+    fn distant_entities_get_sent_despite_a_tight_budget() {
+        let delta_size = wire::serialized_size(&moved(NetId::Orc(0))).unwrap();
+        let budget = delta_size * 3;
+        let interval = SNAPSHOT_INTERVAL_TICKS as f32 / 60.0;
+
+        // Close players change every snapshot and alone would fill the budget forever
+        let mut ids: Vec<_> = (0..10).map(NetId::Player).collect();
+        let far_orc = NetId::Orc(10);
+        ids.push(far_orc);
+
+        let mut priorities = HashMap::new();
+        let mut far_orc_sent = false;
+
+        for _ in 0..200 {
+            let updates = ids.iter().map(|id| moved(*id)).collect();
+            let (sent, remaining) = fit_updates(updates, &priorities, 0, budget, |id| {
+                let distance = if *id == far_orc { 1000.0 } else { 0.0 };
+                priority_rate(id, distance, 1000.0) * interval
+            });
+            priorities = remaining;
+
+            let size: u64 = sent
+                .iter()
+                .map(|delta| wire::serialized_size(delta).unwrap())
+                .sum();
+            assert!(size <= budget);
+            assert_eq!(sent.len() + priorities.len(), ids.len());
+
+            far_orc_sent |= sent.iter().any(|delta| delta.id == far_orc);
+        }
+
+        assert!(far_orc_sent);
+    }
+
+    #[test]
+    fn a_tiny_budget_still_sends_the_most_urgent_update() {
+        let priorities = HashMap::from([(NetId::Orc(2), 5.0)]);
+        let updates = vec![moved(NetId::Player(1)), moved(NetId::Orc(2))];
+
+        let (sent, remaining) = fit_updates(updates, &priorities, 0, 0, |_| 1.0);
+
+        assert_eq!(sent, vec![moved(NetId::Orc(2))]);
+        assert_eq!(remaining, HashMap::from([(NetId::Player(1), 1.0)]));
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+
+use crate::{
+    config::ServerSettings,
+    orc::{events::SpawnOrc, Orc, OrcKind, SIGHT_RANGE},
+    session::HeldSessions,
+    Players, SM,
+};
+use shared::*;
+
+/// Orcs in the first wave with a single player
+const BASE_WAVE_SIZE: f32 = 6.0;
+/// Orcs added by every further wave
+const WAVE_SIZE_GROWTH: f32 = 3.0;
+/// Share of a wave added for every player beyond the first
+const WAVE_SIZE_PER_PLAYER: f32 = 0.5;
+/// Seconds between two orcs of a wave appearing
+const SPAWN_INTERVAL: f64 = 0.5;
+/// The rest of a wave waits while this many orcs are alive
+const MAX_ALIVE_ORCS: usize = 40;
+/// Distance from a player at which orcs are surely off their screen
+const VIEW_DISTANCE: f32 = 800.0;
+/// How much further than `VIEW_DISTANCE` orcs may appear. Stays within sight of the
+/// player they appear next to, so they start chasing right away instead of wandering off.
+const SPAWN_RING_WIDTH: f32 = (SIGHT_RANGE - VIEW_DISTANCE) * 0.75;
+const SPAWN_POINT_ATTEMPTS: usize = 8;
+
+enum WavePhase {
+    /// Resting until the next wave
+    Intermission { ends_at: f64 },
+    /// Orcs are still coming
+    Active { to_spawn: u32, next_spawn_at: f64 },
+}
+
+#[derive(Resource)]
+struct WaveDirector {
+    /// The current wave, or the last one during an intermission
+    wave: u32,
+    /// Orcs in the current wave
+    size: u32,
+    phase: WavePhase,
+}
+
+impl WaveDirector {
+    fn new(first_wave_at: f64) -> Self {
+        Self {
+            wave: 0,
+            size: 0,
+            phase: WavePhase::Intermission {
+                ends_at: first_wave_at,
+            },
+        }
+    }
+
+    fn status(&self, now: f64) -> ServerMessage {
+        match self.phase {
+            WavePhase::Intermission { ends_at } => ServerMessage::WaveEnded {
+                wave: self.wave,
+                next_wave_in: (ends_at - now).max(0.0) as f32,
+            },
+            WavePhase::Active { .. } => ServerMessage::WaveStarted {
+                wave: self.wave,
+                orcs: self.size,
+            },
+        }
+    }
+}
+
+pub struct WavePlugin;
+
+impl Plugin for WavePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(WaveDirector::new(0.0))
+            .add_system(wave_director_system)
+            .add_system(wave_status_system.after(wave_director_system));
+    }
+}
+
+/// Send waves of orcs at the players, each bigger than the last, with a rest in between.
+/// Starts over once everyone left.
+#[allow(clippy::too_many_arguments)]
+fn wave_director_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    settings: Res<ServerSettings>,
+    players: Res<Players>,
+    held_sessions: Res<HeldSessions>,
+    mut director: ResMut<WaveDirector>,
+    mut spawn_orc_events: EventWriter<SpawnOrc>,
+    mut server_msg_events: EventWriter<SM>,
+    orc_query: Query<Entity, With<Orc>>,
+    transform_query: Query<&Transform>,
+) {
+    let now = time.elapsed_seconds_f64();
+
+    if players.0.is_empty() {
+        // Dropped players may still come back to their wave
+        if held_sessions.0.is_empty() {
+            if director.wave > 0 {
+                println!("Everyone left, waves start over");
+            }
+            for entity in orc_query.iter() {
+                commands.entity(entity).despawn();
+            }
+            *director = WaveDirector::new(now + settings.wave_intermission);
+        }
+        return;
+    }
+
+    let director = &mut *director;
+
+    match &mut director.phase {
+        WavePhase::Intermission { ends_at } => {
+            if now < *ends_at {
+                return;
+            }
+
+            director.wave += 1;
+            director.size = wave_size(director.wave, players.0.len());
+            director.phase = WavePhase::Active {
+                to_spawn: director.size,
+                next_spawn_at: now,
+            };
+
+            println!("Wave {} started with {} orcs", director.wave, director.size);
+            broadcast_status(director, now, &players, &mut server_msg_events);
+        }
+        WavePhase::Active {
+            to_spawn,
+            next_spawn_at,
+        } => {
+            if now < *next_spawn_at {
+                return;
+            }
+
+            let alive = orc_query.iter().count();
+
+            if *to_spawn == 0 {
+                // Checked at least one interval after the last spawn, so it is counted
+                if alive == 0 {
+                    println!("Wave {} cleared", director.wave);
+                    director.phase = WavePhase::Intermission {
+                        ends_at: now + settings.wave_intermission,
+                    };
+                    broadcast_status(director, now, &players, &mut server_msg_events);
+                }
+                return;
+            }
+
+            if alive >= MAX_ALIVE_ORCS {
+                return;
+            }
+
+            let player_positions: Vec<Vec2> = players
+                .0
+                .values()
+                .filter_map(|player_info| transform_query.get(player_info.entity).ok())
+                .map(|player_tf| player_tf.translation.truncate())
+                .collect();
+
+            if let Some((position, target)) = spawn_point(&player_positions) {
+                let to_target = target - position;
+                spawn_orc_events.send(SpawnOrc {
+                    kind: orc_kind(director.wave, rand::random::<f32>()),
+                    position,
+                    direction: to_target.y.atan2(to_target.x),
+                });
+
+                *to_spawn -= 1;
+                *next_spawn_at = now + SPAWN_INTERVAL;
+            }
+        }
+    }
+}
+
+/// Tell players who just joined which wave they are in
+fn wave_status_system(
+    mut informed: Local<HashSet<u64>>,
+    time: Res<Time>,
+    players: Res<Players>,
+    director: Res<WaveDirector>,
+    mut server_msg_events: EventWriter<SM>,
+) {
+    informed.retain(|client_id| players.0.contains_key(client_id));
+
+    for client_id in players.0.keys() {
+        if informed.insert(*client_id) {
+            server_msg_events.send((*client_id, director.status(time.elapsed_seconds_f64())));
+        }
+    }
+}
+
+fn broadcast_status(
+    director: &WaveDirector,
+    now: f64,
+    players: &Players,
+    server_msg_events: &mut EventWriter<SM>,
+) {
+    for client_id in players.0.keys() {
+        server_msg_events.send((*client_id, director.status(now)));
+    }
+}
+
+fn wave_size(wave: u32, player_count: usize) -> u32 {
+    let base = BASE_WAVE_SIZE + WAVE_SIZE_GROWTH * wave.saturating_sub(1) as f32;
+    let player_scale = 1.0 + WAVE_SIZE_PER_PLAYER * player_count.saturating_sub(1) as f32;
+
+    (base * player_scale).round() as u32
+}
+
+/// Kind of an orc in wave `wave`, for a `roll` between 0 and 1. Runners show up from the
+/// third wave and brutes from the fifth, both getting more common up to a limit.
+fn orc_kind(wave: u32, roll: f32) -> OrcKind {
+    let runner_share = (0.05 * wave.saturating_sub(2) as f32).min(0.3);
+    let brute_share = (0.04 * wave.saturating_sub(4) as f32).min(0.2);
+
+    if roll < brute_share {
+        OrcKind::Brute
+    } else if roll < brute_share + runner_share {
+        OrcKind::Runner
+    } else {
+        OrcKind::Grunt
+    }
+}
+
+/// A point just out of view of every player, along with the player it is next to
+fn spawn_point(player_positions: &[Vec2]) -> Option<(Vec2, Vec2)> {
+    if player_positions.is_empty() {
+        return None;
+    }
+
+    let limit = Vec2::splat(WORLD_HALF_EXTENT - 1.0);
+
+    for _ in 0..SPAWN_POINT_ATTEMPTS {
+        let target = player_positions[rand::random::<usize>() % player_positions.len()];
+        let angle = rand::random::<f32>() * std::f32::consts::TAU;
+        let distance = VIEW_DISTANCE + rand::random::<f32>() * SPAWN_RING_WIDTH;
+        let position = (target + Vec2::from_angle(angle) * distance).clamp(-limit, limit);
+
+        if player_positions
+            .iter()
+            .all(|player_position| player_position.distance(position) > VIEW_DISTANCE)
+        {
+            return Some((position, target));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waves_grow_with_wave_number_and_players() {
+        assert_eq!(wave_size(1, 1), 6);
+        assert_eq!(wave_size(2, 1), 9);
+        assert_eq!(wave_size(1, 3), 12);
+        assert!(wave_size(10, 4) > wave_size(10, 2));
+    }
+
+    #[test]
+    fn tougher_orcs_show_up_in_later_waves() {
+        for roll in [0.0, 0.5, 0.99] {
+            assert_eq!(orc_kind(1, roll), OrcKind::Grunt);
+            assert_eq!(orc_kind(2, roll), OrcKind::Grunt);
+        }
+
+        assert_eq!(orc_kind(3, 0.0), OrcKind::Runner);
+        assert_eq!(orc_kind(5, 0.0), OrcKind::Brute);
+        assert_eq!(orc_kind(100, 0.99), OrcKind::Grunt);
+    }
+
+    #[test]
+    fn spawn_points_are_out_of_view_but_in_sight() {
+        let players = [Vec2::ZERO, Vec2::new(500.0, 0.0)];
+
+        for _ in 0..100 {
+            if let Some((position, target)) = spawn_point(&players) {
+                assert!(players.contains(&target));
+                assert!(target.distance(position) < SIGHT_RANGE);
+                assert!(players
+                    .iter()
+                    .all(|player| player.distance(position) > VIEW_DISTANCE));
+            }
+        }
+    }
+}
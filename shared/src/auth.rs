@@ -0,0 +1,113 @@
+use std::{
+    error::Error,
+    io::{BufRead, Write},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use bevy_renet::renet::{ConnectToken, NETCODE_KEY_BYTES, NETCODE_USER_DATA_BYTES};
+
+use crate::{wire, UserData, PROTOCOL_ID};
+
+/// How long an issued connect token can be used to start a connection
+pub const TOKEN_EXPIRE_SECONDS: u64 = 300;
+/// How long a connection made with the token may go silent before timing out
+pub const TOKEN_TIMEOUT_SECONDS: i32 = 15;
+
+pub type PrivateKey = [u8; NETCODE_KEY_BYTES];
+
+/// Serialize user data into the fixed size buffer netcode carries during the handshake.
+pub fn serialize_user_data(
+    user_data: &UserData,
+) -> Result<[u8; NETCODE_USER_DATA_BYTES], Box<dyn Error>> {
+    // Check whether the serialized data would be small enough
+    if wire::serialized_size(user_data)? as usize > NETCODE_USER_DATA_BYTES {
+        return Err(format!(
+            "User data is too large (>{} bytes)",
+            NETCODE_USER_DATA_BYTES
+        )
+        .into());
+    }
+
+    let user_data = wire::serialize(user_data)?;
+    let mut data_array = [0; NETCODE_USER_DATA_BYTES];
+    data_array[..user_data.len()].copy_from_slice(&user_data);
+
+    Ok(data_array)
+}
+
+/// Parse a private key written as 64 hexadecimal characters.
+pub fn parse_private_key(hex: &str) -> Result<PrivateKey, String> {
+    let hex = hex.trim();
+    if hex.len() != NETCODE_KEY_BYTES * 2 || !hex.is_ascii() {
+        return Err(format!(
+            "Private key must be {} hexadecimal characters",
+            NETCODE_KEY_BYTES * 2
+        ));
+    }
+
+    let mut key = [0; NETCODE_KEY_BYTES];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| "Private key contains non-hexadecimal characters".to_owned())?;
+    }
+
+    Ok(key)
+}
+
+pub fn format_private_key(key: &PrivateKey) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Issue a connect token signed with the server's private key. The client id and user data
+/// are sealed inside the token, so clients can neither pick their own id nor alter them.
+pub fn issue_connect_token(
+    current_time: Duration,
+    client_id: u64,
+    server_addresses: Vec<SocketAddr>,
+    user_data: &UserData,
+    private_key: &PrivateKey,
+) -> Result<ConnectToken, Box<dyn Error>> {
+    let user_data = serialize_user_data(user_data)?;
+
+    ConnectToken::generate(
+        current_time,
+        PROTOCOL_ID,
+        TOKEN_EXPIRE_SECONDS,
+        client_id,
+        TOKEN_TIMEOUT_SECONDS,
+        server_addresses,
+        Some(&user_data),
+        private_key,
+    )
+    .map_err(|e| format!("Failed to generate connect token: {:?}", e).into())
+}
+
+/// Write a connect token to a token file, after the server address it was issued for.
+/// Tokens keep their addresses to themselves, so clients read it from the file instead.
+pub fn write_token_file(
+    writer: &mut impl Write,
+    server_addr: SocketAddr,
+    connect_token: &ConnectToken,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(writer, "{}", server_addr)?;
+    connect_token.write(writer)?;
+
+    Ok(())
+}
+
+/// Read the server address and connect token of a token file
+pub fn read_token_file(
+    reader: &mut impl BufRead,
+) -> Result<(SocketAddr, ConnectToken), Box<dyn Error>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let server_addr = line
+        .trim_end()
+        .parse()
+        .map_err(|_| "Token file lists no valid server address")?;
+    let connect_token =
+        ConnectToken::read(reader).map_err(|e| format!("Invalid connect token: {:?}", e))?;
+
+    Ok((server_addr, connect_token))
+}
@@ -0,0 +1,96 @@
+use bevy_renet::renet::{
+    ChannelConfig, ChunkChannelConfig, ReliableChannelConfig, RenetConnectionConfig,
+    UnreliableChannelConfig,
+};
+
+use crate::{ClientMessage, ServerMessage};
+
+/// Channels shared by the client and the server. Both sides send and receive on all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// Lifecycle events and chat, which must arrive and stay in order
+    ReliableOrdered,
+    /// High frequency state, where only the latest value matters
+    Unreliable,
+    /// Large reliable messages that get split into slices, like snapshots. Also carries
+    /// everything a client must read before the rest of the game state.
+    Chunk,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [
+        Channel::ReliableOrdered,
+        Channel::Unreliable,
+        Channel::Chunk,
+    ];
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Channel::ReliableOrdered => 0,
+            Channel::Unreliable => 1,
+            Channel::Chunk => 2,
+        }
+    }
+
+    pub fn channels_config() -> Vec<ChannelConfig> {
+        vec![
+            ReliableChannelConfig {
+                channel_id: Channel::ReliableOrdered.id(),
+                ..Default::default()
+            }
+            .into(),
+            UnreliableChannelConfig {
+                channel_id: Channel::Unreliable.id(),
+                ..Default::default()
+            }
+            .into(),
+            ChunkChannelConfig {
+                channel_id: Channel::Chunk.id(),
+                ..Default::default()
+            }
+            .into(),
+        ]
+    }
+}
+
+pub fn connection_config() -> RenetConnectionConfig {
+    RenetConnectionConfig {
+        send_channels_config: Channel::channels_config(),
+        receive_channels_config: Channel::channels_config(),
+        ..Default::default()
+    }
+}
+
+impl ServerMessage {
+    pub fn channel(&self) -> Channel {
+        match self {
+            ServerMessage::StateSnapshot { .. }
+            | ServerMessage::PlayerInputAck { .. }
+            | ServerMessage::Pong { .. } => Channel::Unreliable,
+            ServerMessage::SpawnEntities { .. }
+            | ServerMessage::DespawnEntities { .. }
+            | ServerMessage::SpawnProjectile { .. }
+            | ServerMessage::DespawnProjectile { .. }
+            | ServerMessage::PlayerHealth { .. }
+            | ServerMessage::PlayerDowned { .. }
+            | ServerMessage::PlayerRespawned { .. }
+            | ServerMessage::WaveStarted { .. }
+            | ServerMessage::WaveEnded { .. }
+            | ServerMessage::ChatMessage { .. } => Channel::ReliableOrdered,
+            ServerMessage::Disconnected { .. }
+            | ServerMessage::Welcome { .. }
+            | ServerMessage::WorldSnapshot { .. } => Channel::Chunk,
+        }
+    }
+}
+
+impl ClientMessage {
+    pub fn channel(&self) -> Channel {
+        match self {
+            ClientMessage::PlayerInput { .. }
+            | ClientMessage::SnapshotAck { .. }
+            | ClientMessage::Ping { .. } => Channel::Unreliable,
+            ClientMessage::Shoot { .. } | ClientMessage::ChatMessage(_) => Channel::ReliableOrdered,
+        }
+    }
+}
@@ -1,38 +1,208 @@
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use movement::PlayerInput;
+use snapshot::{EntityDelta, NetId};
+
+pub mod auth;
+pub mod channels;
+pub mod movement;
+pub mod snapshot;
+pub mod username;
+pub mod wire;
+
+#[cfg(test)]
+mod tests;
+
+/// Netcode protocol id. Clients with a different id are dropped by netcode without any
+/// explanation, so this should only change if the transport itself becomes incompatible.
 pub const PROTOCOL_ID: u64 = 1;
+/// Version of the message schema, checked by the server when a client connects. Bump this
+/// whenever `UserData`, `ServerMessage` or `ClientMessage` change.
+pub const PROTOCOL_VERSION: u32 = 16;
+pub const DEFAULT_SERVER_PORT: u16 = 5678;
+/// Half the side length of the square world around the origin
+pub const WORLD_HALF_EXTENT: f32 = 8192.0;
+/// World units per second
+pub const PROJECTILE_SPEED: f32 = 2000.0;
+/// Seconds a projectile flies if it doesn't hit anything
+pub const PROJECTILE_LIFETIME: f32 = 0.75;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserData {
+    /// Must stay the first field, so mismatched versions can still be detected
+    pub protocol_version: u32,
     pub username: String,
+    /// Token from the `Welcome` of an earlier connection, to take over its player again
+    pub resume_token: Option<u64>,
+}
+
+impl UserData {
+    pub fn new(username: String) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            username,
+            resume_token: None,
+        }
+    }
+
+    /// Read only the protocol version of serialized user data, which works even if the
+    /// rest of it comes from an incompatible version.
+    pub fn read_protocol_version(serialized: &[u8]) -> Option<u32> {
+        wire::deserialize(serialized).ok()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub enum ServerMessage {
-    PlayerJoined {
+pub enum EntitySnapshot {
+    Player {
         id: u64,
         username: String,
+        #[serde(with = "wire::position")]
         position: Vec2,
+        #[serde(with = "wire::angle")]
+        rotation: f32,
+        /// Share of the maximum health left
+        #[serde(with = "wire::health")]
+        health: f32,
     },
-    PlayerLeft {
+    Orc {
         id: u64,
+        #[serde(with = "wire::position")]
+        position: Vec2,
+        #[serde(with = "wire::angle")]
+        rotation: f32,
+        #[serde(with = "wire::health")]
+        health: f32,
+    },
+}
+
+/// Why the server dropped a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectKind {
+    /// Refused while joining, like for an invalid username
+    Rejected,
+    VersionMismatch,
+    ServerFull,
+    Kicked,
+    Banned,
+    ServerShutdown,
+}
+
+impl DisconnectKind {
+    pub fn title(&self) -> &'static str {
+        match self {
+            DisconnectKind::Rejected => "Connection rejected",
+            DisconnectKind::VersionMismatch => "Version mismatch",
+            DisconnectKind::ServerFull => "Server full",
+            DisconnectKind::Kicked => "Kicked",
+            DisconnectKind::Banned => "Banned",
+            DisconnectKind::ServerShutdown => "Server shut down",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ServerMessage {
+    /// Sent right before the server drops a client. Must stay the first variant with the
+    /// reason as its first field, see `read_disconnect_reason`.
+    Disconnected {
+        reason: String,
+        kind: DisconnectKind,
     },
-    PlayerTransformUpdate {
+
+    /// Sent to a client once the server accepted it, before the world snapshot
+    Welcome {
+        /// Id of the client's own player
         id: u64,
+        /// The name the player joined with, which may differ from the requested one
+        username: String,
+        server_name: String,
+        motd: String,
+        /// Server ticks per second
+        tick_rate: f64,
+        /// Where the player is, which is only away from the spawn when resuming
+        #[serde(with = "wire::position")]
         position: Vec2,
-        rotation: f32,
+        /// Lets the client take over the same player if its connection drops
+        resume_token: u64,
+    },
+    /// Every entity within the client's area of interest, sent once to a client that just
+    /// joined. The client handles this before any other message from the server.
+    WorldSnapshot {
+        entities: Vec<EntitySnapshot>,
+    },
+
+    /// State of every entity the client knows about, except its own player, as a delta
+    /// against the snapshot of tick `baseline`. Without a baseline it contains everything.
+    StateSnapshot {
+        tick: u64,
+        baseline: Option<u64>,
+        updates: Vec<EntityDelta>,
+        removed: Vec<NetId>,
     },
 
-    SpawnOrc {
+    /// Entities which came into the client's area of interest
+    SpawnEntities {
+        entities: Vec<EntitySnapshot>,
+    },
+    /// Entities which left the client's area of interest or stopped existing
+    DespawnEntities {
+        ids: Vec<NetId>,
+    },
+    /// Authoritative position of the recipient's own player after applying every input
+    /// up to `sequence`
+    PlayerInputAck {
+        sequence: u32,
+        #[serde(with = "wire::position")]
+        position: Vec2,
+    },
+    /// A projectile which starts at `position` at server tick `tick`, flying in `direction`
+    /// at `PROJECTILE_SPEED` for at most `PROJECTILE_LIFETIME`
+    SpawnProjectile {
         id: u64,
+        /// Player id of the shooter
+        owner: u64,
+        tick: u64,
+        #[serde(with = "wire::position")]
         position: Vec2,
+        #[serde(with = "wire::angle")]
         direction: f32,
     },
-    OrcTransformUpdate {
+    /// A projectile which stops existing at server tick `tick`, like when it hit something
+    DespawnProjectile {
         id: u64,
+        tick: u64,
+    },
+    /// Health of the recipient's own player, whenever it changes
+    PlayerHealth {
+        health: f32,
+        max_health: f32,
+    },
+    /// The recipient's own player went down and can't do anything until it respawns
+    PlayerDowned {
+        respawn_in: f32,
+    },
+    /// The recipient's own player is back on its feet at `position`
+    PlayerRespawned {
+        #[serde(with = "wire::position")]
         position: Vec2,
-        rotation: f32,
+    },
+    /// A wave of `orcs` orcs started coming. Also sent to players joining during a wave.
+    WaveStarted {
+        wave: u32,
+        orcs: u32,
+    },
+    /// Every orc of wave `wave` is gone, the next wave starts in `next_wave_in` seconds.
+    /// Players joining before the first wave get this with `wave` 0.
+    WaveEnded {
+        wave: u32,
+        next_wave_in: f32,
+    },
+    /// Answer to a ping, with the server tick at the time of answering
+    Pong {
+        client_time: f64,
+        tick: u64,
     },
 
     ChatMessage {
@@ -41,9 +211,36 @@ pub enum ServerMessage {
     },
 }
 
+impl ServerMessage {
+    /// Read only the reason of a serialized `Disconnected` message, which works even if
+    /// the message comes from an incompatible version.
+    pub fn read_disconnect_reason(serialized: &[u8]) -> Option<String> {
+        match wire::deserialize::<(u32, String)>(serialized) {
+            Ok((0, reason)) => Some(reason),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
-    PlayerTransformUpdate { position: Vec2, rotation: f32 },
-    Shoot { direction: f32 },
+    /// The most recent inputs, oldest first
+    PlayerInput {
+        inputs: Vec<(u32, PlayerInput)>,
+    },
+    Shoot {
+        #[serde(with = "wire::angle")]
+        direction: f32,
+        /// Server tick of the world the shooter saw when firing
+        tick: u64,
+    },
+    /// The newest state snapshot the client received, used as the next baseline
+    SnapshotAck {
+        tick: u64,
+    },
+    /// Clock sync request. The server echoes the client's local time back in a pong.
+    Ping {
+        client_time: f64,
+    },
     ChatMessage(String),
 }
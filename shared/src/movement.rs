@@ -0,0 +1,43 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::WORLD_HALF_EXTENT;
+
+pub const PLAYER_SPEED: f32 = 300.0;
+/// Simulated time covered by a single input, on both the client and the server
+pub const INPUT_TIMESTEP: f32 = 1.0 / 60.0;
+/// Inputs the client repeats in every message, so a lost packet doesn't lose movement
+pub const MAX_INPUTS_PER_MESSAGE: usize = 8;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInput {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+    /// Angle the player is aiming at
+    #[serde(with = "crate::wire::angle")]
+    pub aim: f32,
+}
+
+impl PlayerInput {
+    pub fn direction(&self) -> Vec2 {
+        Vec2 {
+            x: (self.right as i32 - self.left as i32) as f32,
+            y: (self.up as i32 - self.down as i32) as f32,
+        }
+        .normalize_or_zero()
+    }
+}
+
+/// Move a player by one input. Both the client prediction and the server run this, so
+/// they end up in the same place given the same inputs.
+pub fn move_player(position: Vec2, input: &PlayerInput) -> Vec2 {
+    let position = position + input.direction() * PLAYER_SPEED * INPUT_TIMESTEP;
+
+    // Positions outside the world can't be sent to clients
+    position.clamp(
+        Vec2::splat(-WORLD_HALF_EXTENT),
+        Vec2::splat(WORLD_HALF_EXTENT),
+    )
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::wire::{quantize_angle, quantize_health, quantize_position};
+
+/// Identifies a replicated entity. Players and orcs have separate id spaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NetId {
+    Player(u64),
+    Orc(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EntityState {
+    pub position: Vec2,
+    /// Angle around the z axis
+    pub rotation: f32,
+    /// Share of the maximum health left, 0 for downed players
+    pub health: f32,
+}
+
+/// Fields of an entity which changed since the baseline. An entity missing from the
+/// baseline has all of its fields set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntityDelta {
+    pub id: NetId,
+    #[serde(with = "crate::wire::option_position")]
+    pub position: Option<Vec2>,
+    #[serde(with = "crate::wire::option_angle")]
+    pub rotation: Option<f32>,
+    #[serde(with = "crate::wire::option_health")]
+    pub health: Option<f32>,
+}
+
+/// State of every replicated entity at one tick
+pub type WorldState = HashMap<NetId, EntityState>;
+
+/// Changes needed to turn `baseline` into `current`, or all of `current` if there is no
+/// baseline. Returns the updated entities and the removed ones.
+pub fn encode_delta(
+    baseline: Option<&WorldState>,
+    current: &WorldState,
+) -> (Vec<EntityDelta>, Vec<NetId>) {
+    let updates = current
+        .iter()
+        .filter_map(|(id, state)| {
+            let old = baseline.and_then(|baseline| baseline.get(id));
+
+            // Changes too small to survive quantization wait until they add up
+            let delta = EntityDelta {
+                id: *id,
+                position: Some(state.position).filter(|position| {
+                    old.is_none_or(|old| {
+                        quantize_position(old.position) != quantize_position(*position)
+                    })
+                }),
+                rotation: Some(state.rotation).filter(|rotation| {
+                    old.is_none_or(|old| quantize_angle(old.rotation) != quantize_angle(*rotation))
+                }),
+                health: Some(state.health).filter(|health| {
+                    old.is_none_or(|old| quantize_health(old.health) != quantize_health(*health))
+                }),
+            };
+
+            // Entities which didn't change at all are left out
+            (delta.position.is_some() || delta.rotation.is_some() || delta.health.is_some())
+                .then_some(delta)
+        })
+        .collect();
+
+    let removed = baseline
+        .map(|baseline| {
+            baseline
+                .keys()
+                .filter(|id| !current.contains_key(id))
+                .copied()
+                .collect()
+        })
+        .unwrap_or_default();
+
+    (updates, removed)
+}
+
+/// Rebuild the full world state from a delta. Fails if the delta refers to fields the
+/// baseline doesn't have, which means the baseline is not the one it was encoded against.
+pub fn apply_delta(
+    baseline: Option<&WorldState>,
+    updates: &[EntityDelta],
+    removed: &[NetId],
+) -> Option<WorldState> {
+    let mut state = baseline.cloned().unwrap_or_default();
+
+    for id in removed {
+        state.remove(id);
+    }
+
+    for delta in updates {
+        let old = state.get(&delta.id);
+
+        let new = EntityState {
+            position: delta.position.or_else(|| old.map(|old| old.position))?,
+            rotation: delta.rotation.or_else(|| old.map(|old| old.rotation))?,
+            health: delta.health.or_else(|| old.map(|old| old.health))?,
+        };
+
+        state.insert(delta.id, new);
+    }
+
+    Some(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world(entities: &[(NetId, Vec2, f32)]) -> WorldState {
+        entities
+            .iter()
+            .map(|(id, position, rotation)| {
+                (
+                    *id,
+                    EntityState {
+                        position: *position,
+                        rotation: *rotation,
+                        health: 1.0,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delta_only_contains_changes() {
+        let baseline = world(&[
+            (NetId::Player(1), Vec2::ZERO, 0.0),
+            (NetId::Orc(1), Vec2::ZERO, 1.0),
+            (NetId::Orc(2), Vec2::ZERO, 1.0),
+        ]);
+        let current = world(&[
+            (NetId::Player(1), Vec2::ZERO, 0.0),
+            (NetId::Orc(1), Vec2::ONE, 1.0),
+            (NetId::Orc(3), Vec2::ONE, 2.0),
+        ]);
+
+        let (mut updates, removed) = encode_delta(Some(&baseline), &current);
+        updates.sort_by_key(|delta| format!("{:?}", delta.id));
+
+        assert_eq!(
+            updates,
+            vec![
+                EntityDelta {
+                    id: NetId::Orc(1),
+                    position: Some(Vec2::ONE),
+                    rotation: None,
+                    health: None,
+                },
+                EntityDelta {
+                    id: NetId::Orc(3),
+                    position: Some(Vec2::ONE),
+                    rotation: Some(2.0),
+                    health: Some(1.0),
+                },
+            ]
+        );
+        assert_eq!(removed, vec![NetId::Orc(2)]);
+        assert_eq!(
+            apply_delta(Some(&baseline), &updates, &removed),
+            Some(current)
+        );
+    }
+
+    #[test]
+    fn full_snapshot_without_baseline() {
+        let current = world(&[(NetId::Player(1), Vec2::ONE, 0.5)]);
+
+        let (updates, removed) = encode_delta(None, &current);
+        assert_eq!(apply_delta(None, &updates, &removed), Some(current));
+    }
+
+    #[test]
+    fn partial_delta_needs_its_baseline() {
+        let updates = vec![EntityDelta {
+            id: NetId::Orc(1),
+            position: Some(Vec2::ONE),
+            rotation: None,
+            health: None,
+        }];
+
+        assert_eq!(apply_delta(None, &updates, &[]), None);
+    }
+}
@@ -0,0 +1,253 @@
+use bevy::prelude::*;
+
+use crate::{
+    movement::PlayerInput,
+    snapshot::{EntityDelta, NetId},
+    *,
+};
+
+/// One instance of every message. The exhaustive matches make adding a variant a compile
+/// error here, so the fingerprint can't silently miss it.
+fn sample_messages() -> (UserData, Vec<ServerMessage>, Vec<ClientMessage>) {
+    let user_data = UserData {
+        resume_token: Some(42),
+        ..UserData::new("player".to_owned())
+    };
+
+    let server_messages = vec![
+        ServerMessage::Disconnected {
+            reason: "reason".to_owned(),
+            kind: DisconnectKind::Kicked,
+        },
+        ServerMessage::Welcome {
+            id: 1,
+            username: "player".to_owned(),
+            server_name: "server".to_owned(),
+            motd: "motd".to_owned(),
+            tick_rate: 60.0,
+            position: Vec2::new(1.0, 2.0),
+            resume_token: 42,
+        },
+        ServerMessage::WorldSnapshot {
+            entities: vec![
+                EntitySnapshot::Player {
+                    id: 1,
+                    username: "player".to_owned(),
+                    position: Vec2::new(1.0, 2.0),
+                    rotation: 0.5,
+                    health: 1.0,
+                },
+                EntitySnapshot::Orc {
+                    id: 2,
+                    position: Vec2::new(3.0, 4.0),
+                    rotation: 0.5,
+                    health: 0.5,
+                },
+            ],
+        },
+        ServerMessage::SpawnEntities {
+            entities: vec![EntitySnapshot::Player {
+                id: 3,
+                username: "other".to_owned(),
+                position: Vec2::new(7.0, 8.0),
+                rotation: 1.5,
+                health: 0.0,
+            }],
+        },
+        ServerMessage::DespawnEntities {
+            ids: vec![NetId::Player(3), NetId::Orc(2)],
+        },
+        ServerMessage::StateSnapshot {
+            tick: 10,
+            baseline: Some(8),
+            updates: vec![EntityDelta {
+                id: NetId::Orc(2),
+                position: Some(Vec2::new(3.0, 4.0)),
+                rotation: None,
+                health: Some(0.25),
+            }],
+            removed: vec![NetId::Player(1)],
+        },
+        ServerMessage::PlayerInputAck {
+            sequence: 1,
+            position: Vec2::new(1.0, 2.0),
+        },
+        ServerMessage::SpawnProjectile {
+            id: 4,
+            owner: 1,
+            tick: 10,
+            position: Vec2::new(1.0, 2.0),
+            direction: 0.5,
+        },
+        ServerMessage::DespawnProjectile { id: 4, tick: 12 },
+        ServerMessage::PlayerHealth {
+            health: 75.0,
+            max_health: 100.0,
+        },
+        ServerMessage::PlayerDowned { respawn_in: 5.0 },
+        ServerMessage::PlayerRespawned {
+            position: Vec2::new(1.0, 2.0),
+        },
+        ServerMessage::WaveStarted { wave: 2, orcs: 9 },
+        ServerMessage::WaveEnded {
+            wave: 2,
+            next_wave_in: 20.0,
+        },
+        ServerMessage::Pong {
+            client_time: 1.5,
+            tick: 10,
+        },
+        ServerMessage::ChatMessage {
+            author: 1,
+            content: "hello".to_owned(),
+        },
+    ];
+
+    for msg in &server_messages {
+        match msg {
+            ServerMessage::Disconnected { .. }
+            | ServerMessage::Welcome { .. }
+            | ServerMessage::WorldSnapshot { .. }
+            | ServerMessage::SpawnEntities { .. }
+            | ServerMessage::DespawnEntities { .. }
+            | ServerMessage::StateSnapshot { .. }
+            | ServerMessage::PlayerInputAck { .. }
+            | ServerMessage::SpawnProjectile { .. }
+            | ServerMessage::DespawnProjectile { .. }
+            | ServerMessage::PlayerHealth { .. }
+            | ServerMessage::PlayerDowned { .. }
+            | ServerMessage::PlayerRespawned { .. }
+            | ServerMessage::WaveStarted { .. }
+            | ServerMessage::WaveEnded { .. }
+            | ServerMessage::Pong { .. }
+            | ServerMessage::ChatMessage { .. } => {}
+        }
+    }
+
+    let client_messages = vec![
+        ClientMessage::PlayerInput {
+            inputs: vec![(
+                1,
+                PlayerInput {
+                    up: true,
+                    down: false,
+                    left: true,
+                    right: false,
+                    aim: 0.5,
+                },
+            )],
+        },
+        ClientMessage::Shoot {
+            direction: 0.5,
+            tick: 10,
+        },
+        ClientMessage::SnapshotAck { tick: 10 },
+        ClientMessage::Ping { client_time: 1.5 },
+        ClientMessage::ChatMessage("hello".to_owned()),
+    ];
+
+    for msg in &client_messages {
+        match msg {
+            ClientMessage::PlayerInput { .. }
+            | ClientMessage::Shoot { .. }
+            | ClientMessage::SnapshotAck { .. }
+            | ClientMessage::Ping { .. }
+            | ClientMessage::ChatMessage(_) => {}
+        }
+    }
+
+    (user_data, server_messages, client_messages)
+}
+
+/// FNV-1a hash over the serialized sample messages
+fn schema_fingerprint() -> u64 {
+    let (user_data, server_messages, client_messages) = sample_messages();
+
+    let mut bytes = wire::serialize(&user_data).unwrap();
+    for msg in &server_messages {
+        bytes.extend(wire::serialize(msg).unwrap());
+    }
+    for msg in &client_messages {
+        bytes.extend(wire::serialize(msg).unwrap());
+    }
+
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[test]
+fn protocol_version_matches_schema() {
+    let fingerprint = schema_fingerprint();
+
+    assert_eq!(
+        (PROTOCOL_VERSION, fingerprint),
+        (16, 0x20b7a8211b684ee2),
+        "The message schema changed (fingerprint {:#x}). Bump PROTOCOL_VERSION and pin the new \
+         version and fingerprint here.",
+        fingerprint
+    );
+}
+
+#[test]
+fn protocol_version_survives_user_data_changes() {
+    let mut serialized = wire::serialize(&UserData::new("player".to_owned())).unwrap();
+    serialized.truncate(4);
+
+    assert_eq!(
+        UserData::read_protocol_version(&serialized),
+        Some(PROTOCOL_VERSION)
+    );
+}
+
+#[test]
+fn token_files_know_their_server() {
+    for server_addr in ["203.0.113.7:5000", "[2001:db8::7]:5000"] {
+        let server_addr = server_addr.parse().unwrap();
+        let token = auth::issue_connect_token(
+            std::time::Duration::ZERO,
+            1,
+            vec![server_addr],
+            &UserData::new("player".to_owned()),
+            &[7; 32],
+        )
+        .unwrap();
+
+        let mut file = Vec::new();
+        auth::write_token_file(&mut file, server_addr, &token).unwrap();
+        let (read_addr, _) = auth::read_token_file(&mut file.as_slice()).unwrap();
+
+        assert_eq!(read_addr, server_addr);
+    }
+}
+
+#[test]
+fn disconnect_reason_survives_message_changes() {
+    let mut serialized = wire::serialize(&ServerMessage::Disconnected {
+        reason: "reason".to_owned(),
+        kind: DisconnectKind::Banned,
+    })
+    .unwrap();
+
+    // An older server which only sent the reason
+    let mut older = serialized.clone();
+    older.truncate(older.len() - 1);
+    assert_eq!(
+        ServerMessage::read_disconnect_reason(&older),
+        Some("reason".to_owned())
+    );
+
+    // A newer server which added fields after the kind
+    serialized.extend([3, 0, 0, 0, 7]);
+    assert_eq!(
+        ServerMessage::read_disconnect_reason(&serialized),
+        Some("reason".to_owned())
+    );
+
+    let pong = wire::serialize(&ServerMessage::Pong {
+        client_time: 0.0,
+        tick: 0,
+    })
+    .unwrap();
+    assert_eq!(ServerMessage::read_disconnect_reason(&pong), None);
+}
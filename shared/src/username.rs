@@ -0,0 +1,80 @@
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 16;
+
+/// Names which could be mistaken for messages from the server itself
+const RESERVED_USERNAMES: [&str; 5] = ["server", "admin", "console", "system", "moderator"];
+
+pub fn validate_username(username: &str) -> Result<(), String> {
+    let length = username.chars().count();
+
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Err(format!(
+            "Username must be {} to {} characters long",
+            MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+        ));
+    }
+
+    if !username
+        .chars()
+        .all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '-')
+    {
+        return Err("Username can only contain letters, digits, '_' and '-'".to_owned());
+    }
+
+    if RESERVED_USERNAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(username))
+    {
+        return Err(format!("The username \"{}\" is reserved", username));
+    }
+
+    Ok(())
+}
+
+/// Append the lowest free number to a valid username until `is_taken` no longer matches it,
+/// shortening the name if the suffix would not fit otherwise.
+pub fn unique_username(username: &str, is_taken: impl Fn(&str) -> bool) -> String {
+    if !is_taken(username) {
+        return username.to_owned();
+    }
+
+    (2..)
+        .map(|n| {
+            let suffix = n.to_string();
+            let base: String = username
+                .chars()
+                .take(MAX_USERNAME_LENGTH - suffix.len())
+                .collect();
+
+            base + &suffix
+        })
+        .find(|candidate| !is_taken(candidate))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_usernames() {
+        assert!(validate_username("bob").is_ok());
+        assert!(validate_username("x").is_err());
+        assert!(validate_username("a_very_long_username").is_err());
+        assert!(validate_username("bob!").is_err());
+        assert!(validate_username("Admin").is_err());
+    }
+
+    #[test]
+    fn suffixes_taken_usernames() {
+        let taken = ["bob", "bob2", "sixteen_chars_ab"];
+        let is_taken = |name: &str| taken.iter().any(|t| t.eq_ignore_ascii_case(name));
+
+        assert_eq!(unique_username("alice", is_taken), "alice");
+        assert_eq!(unique_username("BOB", is_taken), "BOB3");
+        assert_eq!(
+            unique_username("sixteen_chars_ab", is_taken),
+            "sixteen_chars_a2"
+        );
+    }
+}
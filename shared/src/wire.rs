@@ -0,0 +1,267 @@
+use std::f32::consts::{PI, TAU};
+
+use bevy::prelude::*;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use crate::WORLD_HALF_EXTENT;
+
+/// Largest error of a position coordinate after a round trip
+pub const POSITION_PRECISION: f32 = WORLD_HALF_EXTENT / u16::MAX as f32;
+/// Largest error of an angle after a round trip, in radians
+pub const ANGLE_PRECISION: f32 = PI / 65536.0;
+
+/// Integers are written as varints, so ids, ticks and lengths mostly take a single byte.
+/// User data is zero padded to a fixed size, hence the trailing bytes.
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().allow_trailing_bytes()
+}
+
+pub fn serialize<T: ?Sized + Serialize>(value: &T) -> bincode::Result<Vec<u8>> {
+    options().serialize(value)
+}
+
+pub fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> bincode::Result<T> {
+    options().deserialize(bytes)
+}
+
+pub fn serialized_size<T: ?Sized + Serialize>(value: &T) -> bincode::Result<u64> {
+    options().serialized_size(value)
+}
+
+// Quantized values are written as bytes, since varints would make most of them larger
+
+/// Map each coordinate within the world bounds onto 16 bits. Positions outside the world
+/// end up on its edge.
+pub fn quantize_position(position: Vec2) -> [u8; 4] {
+    let quantize = |coordinate: f32| {
+        let t = ((coordinate + WORLD_HALF_EXTENT) / (WORLD_HALF_EXTENT * 2.0)).clamp(0.0, 1.0);
+        ((t * u16::MAX as f32).round() as u16).to_le_bytes()
+    };
+    let [x0, x1] = quantize(position.x);
+    let [y0, y1] = quantize(position.y);
+
+    [x0, x1, y0, y1]
+}
+
+pub fn dequantize_position(bytes: [u8; 4]) -> Vec2 {
+    let dequantize = |quantized: [u8; 2]| {
+        u16::from_le_bytes(quantized) as f32 / u16::MAX as f32 * WORLD_HALF_EXTENT * 2.0
+            - WORLD_HALF_EXTENT
+    };
+
+    Vec2::new(
+        dequantize([bytes[0], bytes[1]]),
+        dequantize([bytes[2], bytes[3]]),
+    )
+}
+
+/// Map any angle onto 16 bits, wrapping it into a single turn
+pub fn quantize_angle(angle: f32) -> [u8; 2] {
+    let turns = angle.rem_euclid(TAU) / TAU;
+    // A full turn rounds up to 65536, which wraps around to 0
+    ((turns * 65536.0).round() as u32 as u16).to_le_bytes()
+}
+
+/// The angle in the range (-PI, PI], like `atan2` returns it
+pub fn dequantize_angle(bytes: [u8; 2]) -> f32 {
+    let angle = u16::from_le_bytes(bytes) as f32 / 65536.0 * TAU;
+
+    if angle > PI {
+        angle - TAU
+    } else {
+        angle
+    }
+}
+
+/// Map a share of the maximum health onto a byte. Rounds up, so anything left stays above
+/// zero.
+pub fn quantize_health(health: f32) -> u8 {
+    (health.clamp(0.0, 1.0) * u8::MAX as f32).ceil() as u8
+}
+
+pub fn dequantize_health(quantized: u8) -> f32 {
+    quantized as f32 / u8::MAX as f32
+}
+
+/// For `#[serde(with = "wire::position")]` on `Vec2` fields
+pub mod position {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(position: &Vec2, serializer: S) -> Result<S::Ok, S::Error> {
+        quantize_position(*position).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec2, D::Error> {
+        Ok(dequantize_position(Deserialize::deserialize(deserializer)?))
+    }
+}
+
+/// For `#[serde(with = "wire::option_position")]` on `Option<Vec2>` fields
+pub mod option_position {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        position: &Option<Vec2>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        position.map(quantize_position).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec2>, D::Error> {
+        Ok(Option::deserialize(deserializer)?.map(dequantize_position))
+    }
+}
+
+/// For `#[serde(with = "wire::angle")]` on `f32` angle fields
+pub mod angle {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(angle: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        quantize_angle(*angle).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+        Ok(dequantize_angle(Deserialize::deserialize(deserializer)?))
+    }
+}
+
+/// For `#[serde(with = "wire::option_angle")]` on `Option<f32>` angle fields
+pub mod option_angle {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(angle: &Option<f32>, serializer: S) -> Result<S::Ok, S::Error> {
+        angle.map(quantize_angle).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<f32>, D::Error> {
+        Ok(Option::deserialize(deserializer)?.map(dequantize_angle))
+    }
+}
+
+/// For `#[serde(with = "wire::health")]` on `f32` health share fields
+pub mod health {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(health: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        quantize_health(*health).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+        Ok(dequantize_health(Deserialize::deserialize(deserializer)?))
+    }
+}
+
+/// For `#[serde(with = "wire::option_health")]` on `Option<f32>` health share fields
+pub mod option_health {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        health: &Option<f32>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        health.map(quantize_health).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<f32>, D::Error> {
+        Ok(Option::deserialize(deserializer)?.map(dequantize_health))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::{EntityDelta, NetId};
+
+    // Allow for the rounding of the float math itself
+    const POSITION_EPSILON: f32 = 1e-3;
+    const ANGLE_EPSILON: f32 = 1e-5;
+
+    fn angle_error(a: f32, b: f32) -> f32 {
+        let difference = (a - b).rem_euclid(TAU);
+        difference.min(TAU - difference)
+    }
+
+    #[test]
+    fn position_round_trip_error_is_bounded() {
+        for i in 0..=1000 {
+            let t = i as f32 / 1000.0;
+            let position = Vec2::new(
+                (t * 2.0 - 1.0) * WORLD_HALF_EXTENT,
+                ((t * 7.3).fract() * 2.0 - 1.0) * WORLD_HALF_EXTENT,
+            );
+
+            let error = (dequantize_position(quantize_position(position)) - position).abs();
+            assert!(
+                error.max_element() <= POSITION_PRECISION + POSITION_EPSILON,
+                "{:?} is off by {:?}",
+                position,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn positions_outside_the_world_are_clamped() {
+        let position = dequantize_position(quantize_position(Vec2::new(1e9, -1e9)));
+        assert_eq!(position, Vec2::new(WORLD_HALF_EXTENT, -WORLD_HALF_EXTENT));
+    }
+
+    #[test]
+    fn angle_round_trip_error_is_bounded() {
+        for i in -2000..=2000 {
+            let angle = i as f32 / 1000.0 * TAU;
+            let decoded = dequantize_angle(quantize_angle(angle));
+
+            assert!(decoded > -PI && decoded <= PI);
+            assert!(
+                angle_error(decoded, angle) <= ANGLE_PRECISION + ANGLE_EPSILON,
+                "{} decoded as {}",
+                angle,
+                decoded
+            );
+        }
+    }
+
+    #[test]
+    fn health_keeps_its_ends() {
+        assert_eq!(dequantize_health(quantize_health(0.0)), 0.0);
+        assert_eq!(dequantize_health(quantize_health(1.0)), 1.0);
+        assert_eq!(dequantize_health(quantize_health(-3.0)), 0.0);
+        // Anything left must not round down to nothing
+        assert!(dequantize_health(quantize_health(0.01)) > 0.0);
+        assert!(dequantize_health(quantize_health(0.001)) > 0.0);
+        assert!(dequantize_health(quantize_health(f32::MIN_POSITIVE)) > 0.0);
+    }
+
+    #[test]
+    fn delta_is_compact() {
+        let delta = EntityDelta {
+            id: NetId::Orc(42),
+            position: Some(Vec2::new(100.0, -200.0)),
+            rotation: Some(1.0),
+            health: Some(0.5),
+        };
+
+        // Variant and id, then option tags with 4 position, 2 rotation and 1 health bytes
+        assert_eq!(serialized_size(&delta).unwrap(), 2 + 5 + 3 + 2);
+
+        let decoded: EntityDelta = deserialize(&serialize(&delta).unwrap()).unwrap();
+        assert_eq!(decoded.id, delta.id);
+        assert!((decoded.position.unwrap() - Vec2::new(100.0, -200.0)).length() < 0.2);
+        assert!(angle_error(decoded.rotation.unwrap(), 1.0) <= ANGLE_PRECISION + ANGLE_EPSILON);
+        assert!((decoded.health.unwrap() - 0.5).abs() <= 1.0 / u8::MAX as f32);
+    }
+}